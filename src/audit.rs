@@ -0,0 +1,117 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use serde_derive::Serialize;
+use tokio::{task, time};
+
+use alloc::sync::{Arc, Weak};
+use core::time::Duration;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// Log of executed block commands, written as one JSON object per line.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+/// A single command invocation, as recorded in the [`AuditLog`].
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    /// Time the command was spawned, in seconds since the Unix epoch
+    pub time: f64,
+    /// Index of the block which spawned the command
+    pub block: usize,
+    /// Path of the executed program
+    pub program: &'a Path,
+    /// Directory the command was executed in
+    pub cwd: &'a Path,
+    /// Names of environment variables overridden for the command. Values
+    /// aren't recorded, since they may be secret.
+    pub env: Vec<&'a str>,
+    /// Exit code of the command, if it exited normally
+    pub exit_status: Option<i32>,
+    /// Time the command took to run, in seconds
+    pub duration: f64,
+    /// Why the command failed to run to completion, if it did
+    pub error: Option<String>,
+}
+
+impl AuditLog {
+    /// Interval at which buffered entries are flushed.
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Open the audit log at `path` for appending, creating it if needed.
+    ///
+    /// Entries are flushed periodically until the log is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Opening `path` may fail.
+    pub fn open(path: &Path) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let log = Arc::new(Self {
+            path: path.to_path_buf(),
+            writer: Mutex::new(BufWriter::new(file)),
+        });
+
+        let weak = Arc::downgrade(&log);
+        task::spawn(Self::flush_periodically(weak));
+
+        Ok(log)
+    }
+
+    async fn flush_periodically(log: Weak<Self>) {
+        let mut interval = time::interval_at(
+            time::Instant::now() + Self::FLUSH_INTERVAL,
+            Self::FLUSH_INTERVAL,
+        );
+        loop {
+            interval.tick().await;
+            let Some(log) = log.upgrade() else {
+                break;
+            };
+            let result = log
+                .writer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush();
+            if let Err(err) = result {
+                log.warn(&err, "failed to flush audit log");
+            }
+        }
+    }
+
+    /// Write `entry` to the log. Failure is logged as a warning; it never
+    /// interrupts the block.
+    pub fn record(&self, entry: &Entry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(err) => {
+                self.warn(&err, "failed to serialize audit log entry");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let result = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(&line);
+        if let Err(err) = result {
+            self.warn(&err, "failed to write audit log");
+        }
+    }
+
+    fn warn(&self, err: &dyn std::error::Error, msg: &str) {
+        tracing::warn!(
+            path = format_args!(r#""{}""#, self.path.display()),
+            err = format_args!("{err}"),
+            "{msg}"
+        );
+    }
+}
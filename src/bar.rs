@@ -2,84 +2,385 @@
 // licensed under GPL-3.0-or-later
 
 use anyhow::Context;
+use cowstr::CowStr;
+use serde::Serialize;
 use serde_json::ser;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use tokio::task;
+use tokio::{task, time};
 use tracing::{field, span, Level};
 
+use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
-use core::hash::{Hash as HashTrait, Hasher};
-use std::collections::hash_map::DefaultHasher;
-use std::io::{stdout, BufWriter, StdoutLock, Write};
-use std::path::PathBuf;
-
-use crate::blocks::Blocks;
-use crate::config::Config;
-use crate::protocol::Header;
+use core::cmp::Reverse;
+use core::fmt::Debug;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audit::AuditLog;
+use crate::block::Global;
+use crate::blocks::{BlockHandle, Blocks};
+use crate::click;
+use crate::config::{ClickEofPolicy, Config, Hook, IdleDim, ReloadPolicy, Source, TomlBar};
+use crate::log::LogLevel;
+use crate::protocol::{self, Body, ClickEvent, Header, Markup, Signal};
+use crate::watch::FileWatcher;
 use crate::Hash;
 
 #[allow(clippy::module_name_repetitions)]
-#[derive(Clone, Copy, Debug)]
-#[repr(u8)]
+#[derive(Clone, Debug)]
 pub enum BarMsg {
     Reload,
+    /// The configuration file changed, so reload it. Unlike
+    /// [`BarMsg::Reload`], failing to read it isn't fatal, since it may be
+    /// in the middle of being edited.
+    ConfigChanged,
+    /// Reread the configuration, but apply it only to the block with the
+    /// given name, which executes its command again. Sent through the
+    /// control socket.
+    ReloadBlock(String),
     ShutDown,
+    /// swaybar continued processing, after stopping. Sent for the header's
+    /// `cont_signal` only if it doesn't also reload the configuration.
+    Continue,
+    /// swaybar stopped processing, which needs nothing done until it
+    /// continues. Sent for the header's `stop_signal` only if it doesn't
+    /// also shut down.
+    Stop,
     RefreshBlocks,
+    /// Send the blocks even if they haven't changed
+    ForceRefresh,
+    /// Send the blocks even if they haven't changed, on behalf of a block
+    /// with `pulse` set, without resetting the idle timer
+    Pulse,
+    /// Switch between verbose and terse logs
+    ToggleLogLevel,
+    /// A block was triggered by its signal, which resets the idle timer
+    Activity,
+    /// A click event was received, to be dispatched to the block it names
+    Click(ClickEvent),
+    /// Standard input, through which click events are received, was closed
+    ClickEof,
+}
+
+/// Cloneable handle through which a [`Bar`] may be sent messages, without
+/// going through operating system signals.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Debug)]
+pub struct BarHandle {
+    tx: mpsc::Sender<BarMsg>,
 }
 
+impl BarHandle {
+    /// Send `msg` to the bar.
+    ///
+    /// # Errors
+    ///
+    /// If the bar has stopped listening, returns the unsent message.
+    pub async fn send(&self, msg: BarMsg) -> Result<(), mpsc::error::SendError<BarMsg>> {
+        self.tx.send(msg).await
+    }
+
+    /// Send `msg` to the bar from outside of the runtime, blocking until it's
+    /// sent.
+    ///
+    /// # Errors
+    ///
+    /// If the bar has stopped listening, returns the unsent message.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called within an asynchronous execution context.
+    pub fn blocking_send(&self, msg: BarMsg) -> Result<(), mpsc::error::SendError<BarMsg>> {
+        self.tx.blocking_send(msg)
+    }
+}
+
+/// Destination of the bar's output.
+pub trait Output: Write + Debug {}
+
+impl<T: Write + Debug> Output for T {}
+
+/// Formatting of the JSON sent through the bar's output.
+#[derive(Clone, Debug)]
+pub struct OutputFormat {
+    /// Whether to write compact JSON. If `None`, only what's pretty by
+    /// default is pretty.
+    compact: Option<bool>,
+    /// Indentation of pretty JSON
+    indent: Vec<u8>,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self {
+            compact: None,
+            indent: vec![b' '; TomlBar::DEFAULT_OUTPUT_INDENT],
+        }
+    }
+}
+
+impl OutputFormat {
+    fn new(toml: &TomlBar) -> Self {
+        Self {
+            compact: toml.compact_output,
+            indent: vec![b' '; toml.output_indent],
+        }
+    }
+
+    /// Let what's pretty by default be pretty, even if configured compact.
+    fn allow_pretty(&mut self) {
+        if self.compact == Some(true) {
+            self.compact = None;
+        }
+    }
+
+    /// Write `value` to `out` as JSON. Unless configured otherwise, it's
+    /// pretty if `pretty_by_default`.
+    pub fn write<W: Write, T: Serialize>(
+        &self,
+        out: W,
+        value: &T,
+        pretty_by_default: bool,
+    ) -> serde_json::Result<()> {
+        let pretty = self.compact.map_or(pretty_by_default, |compact| !compact);
+        if pretty {
+            let formatter = ser::PrettyFormatter::with_indent(&self.indent);
+            let mut serializer = ser::Serializer::with_formatter(out, formatter);
+            value.serialize(&mut serializer)
+        } else {
+            ser::to_writer(out, value)
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug)]
 pub struct Bar {
     header: Header,
     config_path: PathBuf,
+    config_source: Source,
+    profile: Option<String>,
     blocks: Blocks,
+    max_total_length: Option<usize>,
+    powerline: bool,
+    debug_names: bool,
+    reverse: bool,
+    click_eof: ClickEofPolicy,
+    /// Command to run after a clean shutdown
+    post_command: Option<Hook>,
+    idle_dim: Option<IdleDim>,
+    /// Time of the latest activity, from which the idle timer counts
+    last_activity: time::Instant,
+    /// Whether the bar is dimmed, because it's idle
+    dimmed: bool,
+    refresh_signal: Option<Signal>,
+    log_signal: Option<Signal>,
+    reload_signal: Option<Signal>,
+    shutdown_signal: Option<Signal>,
+    reload_signals: bool,
+    /// Whether the signals the bar listens for changed while reloading, so
+    /// its signal handles must be replaced
+    signals_changed: bool,
+    log_level: Option<LogLevel>,
+    output_format: OutputFormat,
+    /// Whether blocks are pretty JSON, unless configured otherwise
+    pretty: bool,
+
+    /// Time the bar was created, from which startup is measured
+    started_at: time::Instant,
+    /// Until when the first refresh waits for every block to be
+    /// initialized, if at all
+    startup_deadline: Option<time::Instant>,
+    /// Whether blocks have been sent since the bar was created
+    sent_first_blocks: bool,
+    /// Whether every block has been initialized since the bar was created
+    initialized: bool,
 
     latest_blocks_hash: Option<Hash>,
+    /// Hashes of each block's body when it was last sent, or `None` if it
+    /// was omitted
+    latest_block_hashes: Vec<Option<Hash>>,
+    /// Index, name, and instance of each block when the blocks were last
+    /// sent, against which click events are matched
+    latest_sent: Vec<(usize, Option<CowStr>, Option<CowStr>)>,
     first_header_hash: Option<Hash>,
 
     rx: mpsc::Receiver<BarMsg>,
     tx: mpsc::Sender<BarMsg>,
+    /// Messages received while coalescing reloads, handled before any others
+    pending: VecDeque<BarMsg>,
 
-    stdout: BufWriter<StdoutLock<'static>>,
+    out: BufWriter<Box<dyn Output>>,
 
     signal_handles_created: bool,
+    /// Whether to reload the configuration whenever its file changes
+    watch_config: bool,
 }
 
 impl Bar {
     /* arbitrary, but not too high. this is only 1KiB of bar messages. */
     const CHANNEL_SIZE: usize = 1024;
 
+    /// Create a bar which writes to standard output.
     pub fn new(config: Config) -> Self {
+        Self::with_writer(config, stdout().lock())
+    }
+
+    /// Create a bar which writes to `writer` rather than standard output,
+    /// like a file, or a buffer whose contents are checked afterward.
+    pub fn with_writer<W: Output + 'static>(config: Config, writer: W) -> Self {
         let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
 
-        let mut blocks = Blocks::new(tx.clone());
+        let started_at = time::Instant::now();
+        let output_format = OutputFormat::new(&config.toml);
+        let post_command = config.post_command();
+        let startup_timeout = config.toml.startup_timeout();
+        let mut blocks = Blocks::new(tx.clone(), config.toml.block_shutdown_timeout());
         blocks.add_all(
             config.toml.blocks.into_iter(),
-            Arc::new(config.toml.body),
-            Arc::new(config.command_dir),
+            Arc::new(Global {
+                body: config.toml.body,
+                command_dir: Arc::new(config.command_dir),
+                env: config.env,
+                audit_log: open_audit_log(config.audit_log.as_deref()),
+                umask: config.toml.umask,
+                shell: config.toml.shell,
+            }),
         );
 
-        let stdout = BufWriter::new(stdout().lock());
+        let out = BufWriter::new(Box::new(writer) as Box<dyn Output>);
 
         Self {
             header: config.toml.header,
             config_path: config.path,
+            config_source: config.source,
+            profile: config.profile,
             blocks,
+            max_total_length: config.toml.max_total_length,
+            powerline: config.toml.powerline,
+            debug_names: config.toml.debug_names,
+            reverse: config.toml.reverse,
+            click_eof: config.toml.click_eof,
+            post_command,
+            idle_dim: config.toml.idle_dim,
+            last_activity: time::Instant::now(),
+            dimmed: false,
+            refresh_signal: config.toml.refresh_signal,
+            log_signal: config.toml.log_signal,
+            reload_signal: config.toml.reload_signal,
+            shutdown_signal: config.toml.shutdown_signal,
+            reload_signals: config.toml.reload_signals,
+            signals_changed: false,
+            log_level: None,
+            output_format,
+            pretty: false,
+            started_at,
+            startup_deadline: startup_timeout.map(|timeout| started_at + timeout),
+            sent_first_blocks: false,
+            initialized: false,
             latest_blocks_hash: None,
+            latest_block_hashes: Vec::new(),
+            latest_sent: Vec::new(),
             first_header_hash: None,
             rx,
             tx: tx.clone(),
-            stdout,
+            pending: VecDeque::new(),
+            out,
             signal_handles_created: true,
+            watch_config: false,
         }
     }
 
-    /// Send the configured [`Header`] through standard
-    /// output.
+    /// Switch between verbose and terse logs, if the log level can be
+    /// changed.
+    fn toggle_log_level(&self) {
+        if let Some(ref log_level) = self.log_level {
+            match log_level.toggle() {
+                Ok(level) => tracing::info!(%level, "changed log level"),
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "failed to change log level");
+                }
+            }
+        } else {
+            tracing::warn!("log level can't be changed");
+        }
+    }
+
+    /// Watch the configuration file, if enabled, sending
+    /// [`BarMsg::ConfigChanged`] whenever it changes.
+    fn config_watch_handle(&self) -> Option<task::JoinHandle<()>> {
+        if !self.watch_config {
+            return None;
+        }
+        if !matches!(self.config_source, Source::File { .. }) {
+            tracing::warn!("configuration wasn't read from a file, so it isn't watched");
+            return None;
+        }
+        let bar = self.handle();
+        let span = span!(
+            Level::INFO,
+            "bar_config_watch",
+            path = format_args!(r#""{}""#, self.config_path.display())
+        );
+        let mut watcher = FileWatcher::new(self.config_path.clone());
+        Some(task::spawn(async move {
+            span.in_scope(|| tracing::debug!("watching configuration file"));
+            loop {
+                watcher.changed().await;
+                if bar.send(BarMsg::ConfigChanged).await.is_err() {
+                    /* the bar shut down */
+                    break;
+                }
+            }
+        }))
+    }
+
+    /// Start reading click events from standard input, if enabled by the
+    /// header.
+    fn start_click_reader(&self) {
+        if self.header.click_events == Some(true) {
+            if let Err(err) = click::spawn_reader(self.handle()) {
+                tracing::error!("failed to start reading click events: {err}");
+            }
+        }
+    }
+
+    /// Get a handle through which messages may be sent to the bar while it
+    /// listens.
+    pub fn handle(&self) -> BarHandle {
+        BarHandle {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Set whether to write blocks as pretty JSON, which is easier to read
+    /// when debugging. This overrides the configuration, even when reloaded.
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+        if pretty {
+            self.output_format.allow_pretty();
+        }
+    }
+
+    /// Set whether to reload the configuration whenever its file changes.
+    pub fn set_watch_config(&mut self, watch_config: bool) {
+        self.watch_config = watch_config;
+    }
+
+    /// Set the handle through which `log_signal` changes the log level.
+    pub fn set_log_level(&mut self, log_level: LogLevel) {
+        self.log_level = Some(log_level);
+    }
+
+    /// Send the configured [`Header`] through the bar's output.
     ///
     /// # Errors
     ///
-    /// Writing to standard output may fail.
+    /// Writing to the output may fail.
     pub fn write_header(&mut self) -> anyhow::Result<()> {
         let header = self.header;
         let span = span!(
@@ -100,9 +401,10 @@ impl Bar {
         }
         let _enter = span.enter();
 
-        ser::to_writer(&mut self.stdout, &self.header)?;
-        write!(self.stdout, "\n[")?;
-        self.stdout.flush()?;
+        self.output_format
+            .write(&mut self.out, &self.header, false)?;
+        write!(self.out, "\n[")?;
+        self.out.flush()?;
 
         tracing::trace!("sent header");
 
@@ -115,28 +417,218 @@ impl Bar {
     }
 
     pub async fn reload(&mut self) -> anyhow::Result<()> {
-        let new_config =
-            Config::read_from_path(&self.config_path).context("failed to reload config")?;
+        let new_config = self.reread_config()?;
+        self.apply_config(new_config).await
+    }
+
+    /// Reload the configuration, in response to [`BarMsg::Reload`], along
+    /// with any other reloads which are queued. If a shutdown is queued, the
+    /// reload is skipped, since it would be for nothing.
+    async fn reload_coalesced(&mut self) -> anyhow::Result<()> {
+        let (coalesced, shut_down) = self.coalesce_reloads();
+        if shut_down {
+            tracing::debug!(coalesced, "shutdown is queued, skipping reload");
+            return Ok(());
+        }
+        tracing::info!(coalesced, "reloading configuration");
+        self.wake().await?;
+        self.reload().await
+    }
+
+    /// Read the configuration again, from the same path and profile.
+    fn reread_config(&self) -> anyhow::Result<Config> {
+        Config::reread(
+            &self.config_path,
+            &self.config_source,
+            self.profile.as_deref(),
+        )
+        .context("failed to reload config")
+    }
+
+    /// Reload the configuration after its file changed, keeping the current
+    /// one if reading it fails.
+    async fn config_changed(&mut self) -> anyhow::Result<()> {
+        tracing::info!("configuration file changed, reloading");
+        match self.reread_config() {
+            Ok(new_config) => self.apply_config(new_config).await,
+            Err(err) => {
+                tracing::error!(
+                    err = format_args!("{err:#}"),
+                    "keeping the current configuration"
+                );
+                Ok(())
+            }
+        }
+    }
 
-        if let Some(old) = self.first_header_hash {
-            let new = Hash::new(&new_config.toml.header);
+    /// Take the signals `new_config` listens for, marking the signal handles
+    /// to be replaced if they changed. The rest of the header is kept, since
+    /// it's only sent once.
+    fn adopt_signals(&mut self, new_config: &Config) {
+        let new = &new_config.toml;
+        if (
+            self.header.cont_signal,
+            self.header.stop_signal,
+            self.reload_signal,
+            self.shutdown_signal,
+            self.refresh_signal,
+            self.log_signal,
+        ) != (
+            new.header.cont_signal,
+            new.header.stop_signal,
+            new.reload_signal,
+            new.shutdown_signal,
+            new.refresh_signal,
+            new.log_signal,
+        ) {
+            self.header.cont_signal = new.header.cont_signal;
+            self.header.stop_signal = new.header.stop_signal;
+            self.reload_signal = new.reload_signal;
+            self.shutdown_signal = new.shutdown_signal;
+            self.refresh_signal = new.refresh_signal;
+            self.log_signal = new.log_signal;
+            self.signals_changed = true;
+        }
+    }
+
+    /// Replace `sig_handles` with handles listening for the current signals,
+    /// if they changed while reloading.
+    async fn replace_signal_handles(&mut self, sig_handles: &mut Vec<task::JoinHandle<()>>) {
+        if !self.signals_changed {
+            return;
+        }
+        self.signals_changed = false;
+        tracing::info!("signals changed, listening for the new ones");
+        for handle in sig_handles.drain(..) {
+            handle.abort();
+            crate::await_cancellable(handle).await;
+        }
+        sig_handles.extend(self.spawn_signal_listeners());
+    }
+
+    /// Apply `new_config`, which was read again, in place of the current
+    /// configuration.
+    async fn apply_config(&mut self, new_config: Config) -> anyhow::Result<()> {
+        self.reload_signals = new_config.toml.reload_signals;
+        if self.reload_signals {
+            self.adopt_signals(&new_config);
+        }
+        if self.first_header_hash.is_some()
+            && Hash::new(&self.header) != Hash::new(&new_config.toml.header)
+        {
+            tracing::warn!("changes to the header will not take effect until smolbar is restarted");
+        }
+        for (field, old, new) in [
+            (
+                "reload_signal",
+                self.reload_signal,
+                new_config.toml.reload_signal,
+            ),
+            (
+                "shutdown_signal",
+                self.shutdown_signal,
+                new_config.toml.shutdown_signal,
+            ),
+            (
+                "refresh_signal",
+                self.refresh_signal,
+                new_config.toml.refresh_signal,
+            ),
+            ("log_signal", self.log_signal, new_config.toml.log_signal),
+        ] {
             if old != new {
                 tracing::warn!(
-                    "changes to the header will not take effect until smolbar is restarted"
+                    "changes to {field} will not take effect until smolbar is restarted"
                 );
             }
         }
 
-        self.blocks.remove_all().await;
+        self.blocks
+            .set_remove_timeout(new_config.toml.block_shutdown_timeout());
+        let reload = new_config.toml.reload;
+        if reload == ReloadPolicy::Full {
+            self.blocks.remove_all().await;
+        }
+        self.post_command = new_config.post_command();
         self.config_path = new_config.path;
-        self.blocks.add_all(
-            new_config.toml.blocks.into_iter(),
-            Arc::new(new_config.toml.body),
-            Arc::new(new_config.command_dir),
-        );
+        self.max_total_length = new_config.toml.max_total_length;
+        self.powerline = new_config.toml.powerline;
+        self.debug_names = new_config.toml.debug_names;
+        self.reverse = new_config.toml.reverse;
+        self.click_eof = new_config.toml.click_eof;
+        self.idle_dim.clone_from(&new_config.toml.idle_dim);
+        self.output_format = OutputFormat::new(&new_config.toml);
+        if self.pretty {
+            self.output_format.allow_pretty();
+        }
+        /* the first refresh after reloading must always be sent, regardless
+         * of what was sent before */
+        self.latest_blocks_hash = None;
+        let blocks = new_config.toml.blocks.into_iter();
+        let global = Arc::new(Global {
+            body: new_config.toml.body,
+            command_dir: Arc::new(new_config.command_dir),
+            env: new_config.env,
+            audit_log: open_audit_log(new_config.audit_log.as_deref()),
+            umask: new_config.toml.umask,
+            shell: new_config.toml.shell,
+        });
+        match reload {
+            ReloadPolicy::Full => {
+                self.blocks.add_all(blocks, global);
+                self.refresh_if_empty().await?;
+            }
+            ReloadPolicy::Soft => {
+                self.blocks.reconfigure_all(blocks, global).await;
+                /* blocks which were kept may not change, and would otherwise
+                 * never request a refresh, even if other blocks were removed */
+                self.refresh_blocks().await?;
+            }
+        }
+        self.log_if_static();
         Ok(())
     }
 
+    /// Reread the configuration file, but apply it only to the block named
+    /// `name`, which executes its command again.
+    ///
+    /// # Errors
+    ///
+    /// - Reading the config may fail
+    /// - There may be no block named `name`, either running or in the config
+    pub async fn reload_block(&mut self, name: &str) -> anyhow::Result<()> {
+        let new_config = self.reread_config()?;
+
+        let toml = new_config
+            .toml
+            .blocks
+            .into_iter()
+            .find(|block| block.body.name.as_deref() == Some(name))
+            .with_context(|| format!(r#"no block named "{name}" in config"#))?;
+        let global = Arc::new(Global {
+            body: new_config.toml.body,
+            command_dir: Arc::new(new_config.command_dir),
+            env: new_config.env,
+            audit_log: open_audit_log(new_config.audit_log.as_deref()),
+            umask: new_config.toml.umask,
+            shell: new_config.toml.shell,
+        });
+        if !self.blocks.reload(name, toml, global).await {
+            anyhow::bail!(r#"no running block named "{name}""#);
+        }
+        Ok(())
+    }
+
+    /// Run `post_command`, if configured. This is only done on clean
+    /// shutdown, since after a fatal error, whatever it cleans up after may
+    /// be unreliable.
+    async fn run_post_command(&self) {
+        if let Some(ref hook) = self.post_command {
+            /* failure is already logged, and there's nothing else to do */
+            let _ = hook.run().await;
+        }
+    }
+
     async fn shut_down(&mut self, sig_handles: &mut Vec<task::JoinHandle<()>>) {
         let span = span!(Level::INFO, "bar_shut_down");
         let _enter = span.enter();
@@ -159,47 +651,372 @@ impl Bar {
         );
         let _enter = span.enter();
 
+        if !self.startup_ready() {
+            tracing::trace!("waiting for blocks to initialize, suppressing refresh");
+            return Ok(());
+        }
+
+        let omitted = self.omitted_blocks().await;
+        let blocks = &self.blocks;
+        let is_omitted = |idx: usize| {
+            omitted.get(idx).copied().unwrap_or(false)
+                || blocks
+                    .get(idx)
+                    .map_or(false, |block| block.hidden.load(Ordering::Relaxed))
+        };
+
         // make sure we're not sending the same sequence of blocks
-        let new_hash = {
-            let mut hasher = DefaultHasher::new();
-            for (_handle, _block_tx, body) in self.blocks.iter() {
-                body.read().await.hash(&mut hasher);
+        let mut block_hashes = Vec::with_capacity(self.blocks.len());
+        for (idx, block) in self.blocks.iter().enumerate() {
+            block_hashes.push(if is_omitted(idx) {
+                None
+            } else {
+                Some(Hash::new(&*block.body.read().await))
+            });
+        }
+        let new_hash = Hash::new(&block_hashes);
+        let suppressed = self.latest_blocks_hash == Some(new_hash);
+        if tracing::enabled!(Level::DEBUG) {
+            self.log_changes(&block_hashes, suppressed);
+        }
+        self.latest_block_hashes = block_hashes;
+        if suppressed {
+            tracing::trace!("blocks unchanged, suppressing refresh");
+            return Ok(());
+        }
+
+        let mut shown: Vec<(usize, &BlockHandle)> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !is_omitted(*idx))
+            .collect();
+        if self.reverse {
+            shown.reverse();
+        }
+
+        write!(self.out, "[")?;
+        let mut sent = Vec::with_capacity(shown.len());
+        let mut prev_background = None;
+        for (idx, (block_idx, block)) in shown.iter().enumerate() {
+            // all but first block are preceded by comma
+            if idx != 0 {
+                writeln!(self.out, ",")?;
             }
-            Hash(hasher.finish())
-        };
-        if let Some(old_hash) = self.latest_blocks_hash {
-            if old_hash == new_hash {
-                tracing::trace!("blocks unchanged, suppressing refresh");
-                return Ok(());
+
+            let body = block.body.read().await;
+            let mut body = Cow::Borrowed(&*body);
+
+            /* no separator is drawn between blocks of the same group */
+            let next = shown.get(idx + 1);
+            if block.group.is_some() && next.map(|(_, next)| &next.group) == Some(&block.group) {
+                body.to_mut().separator = Some(false);
             }
-        }
 
-        write!(self.stdout, "[")?;
-        for (idx, (_handle, _block_tx, body)) in self.blocks.iter().enumerate() {
-            ser::to_writer_pretty(&mut self.stdout, &*body.read().await)?;
+            /* identify the block by its configured name, or else its position */
+            if self.debug_names {
+                let name = block
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| CowStr::from(block_idx.to_string()));
+                if body.name.as_ref() != Some(&name) {
+                    body.to_mut().name = Some(name);
+                }
+            }
 
-            // all but last block have comma
-            if idx != self.blocks.len() - 1 {
-                writeln!(self.stdout, ",")?;
+            if self.powerline {
+                let background = body.background.clone();
+                powerline(body.to_mut(), prev_background.as_deref());
+                prev_background = background;
+            }
+
+            if self.dimmed {
+                if let Some(ref idle_dim) = self.idle_dim {
+                    dim(body.to_mut(), idle_dim.opacity);
+                }
             }
+
+            self.output_format
+                .write(&mut self.out, &*body, self.pretty)?;
+            sent.push((*block_idx, body.name.clone(), body.instance.clone()));
         }
-        writeln!(self.stdout, "],")?;
+        writeln!(self.out, "],")?;
 
-        self.stdout.flush()?;
+        self.out.flush()?;
         tracing::trace!("sent block(s)");
+        if !self.sent_first_blocks {
+            self.sent_first_blocks = true;
+            tracing::info!(
+                elapsed = format_args!("{:?}", self.started_at.elapsed()),
+                "sent blocks for the first time"
+            );
+        }
 
         self.latest_blocks_hash = Some(new_hash);
+        self.latest_sent = sent;
+
+        Ok(())
+    }
+
+    /// Returns whether blocks may be sent, rather than waiting for every
+    /// block to be initialized before the first refresh, until
+    /// `startup_timeout` passes. This also logs how long it took for every
+    /// block to be initialized.
+    fn startup_ready(&mut self) -> bool {
+        if !self.initialized && self.blocks.all_initialized() {
+            self.initialized = true;
+            tracing::info!(
+                elapsed = format_args!("{:?}", self.started_at.elapsed()),
+                "every block initialized"
+            );
+        }
+        let Some(deadline) = self.startup_deadline else {
+            return true;
+        };
+        if !self.initialized {
+            if time::Instant::now() < deadline {
+                return false;
+            }
+            tracing::warn!(
+                "startup_timeout passed before every block was initialized, sending blocks anyway"
+            );
+        }
+        self.startup_deadline = None;
+        true
+    }
+
+    /// Log which blocks changed since the previous refresh, given the hashes
+    /// of their bodies (or `None` if they're omitted), and whether the
+    /// refresh was `suppressed` because none did.
+    fn log_changes(&self, block_hashes: &[Option<Hash>], suppressed: bool) {
+        let mut changed = Vec::new();
+        for (idx, hash) in block_hashes.iter().enumerate() {
+            if self.latest_block_hashes.get(idx) != Some(hash) {
+                let block = self.blocks.get(idx).expect("hashes are of blocks");
+                changed.push(match (&block.name, hash) {
+                    (Some(name), Some(_)) => format!(r#"{idx} ("{name}")"#),
+                    (Some(name), None) => format!(r#"{idx} ("{name}", omitted)"#),
+                    (None, Some(_)) => idx.to_string(),
+                    (None, None) => format!("{idx} (omitted)"),
+                });
+            }
+        }
+        tracing::debug!(
+            changed = format_args!("[{}]", changed.join(", ")),
+            forced = self.latest_blocks_hash.is_none(),
+            suppressed,
+            "refresh cycle"
+        );
+    }
 
+    /// Send the blocks if there are none. Otherwise, nothing would request a
+    /// refresh, so the bar would be left with no blocks at all, or with
+    /// blocks which were removed.
+    ///
+    /// # Errors
+    ///
+    /// Writing to the output may fail.
+    async fn refresh_if_empty(&mut self) -> anyhow::Result<()> {
+        if self.blocks.is_empty() {
+            self.refresh_blocks().await?;
+        }
         Ok(())
     }
 
+    /// Wait for the next message, dimming the blocks if the bar becomes idle
+    /// in the meantime. Returns `None` once no more messages can be
+    /// received.
+    ///
+    /// # Errors
+    ///
+    /// Writing to the output may fail.
+    async fn recv(&mut self, span: impl Fn() -> tracing::Span) -> anyhow::Result<Option<BarMsg>> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(Some(msg));
+        }
+        loop {
+            let deadline = self.idle_deadline();
+            tokio::select!(
+                msg = self.rx.recv() => return Ok(msg),
+
+                () = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    let span = span();
+                    let _enter = span.enter();
+                    self.dim_idle().await?;
+                }
+
+                () = time::sleep_until(self.startup_deadline.unwrap_or_else(time::Instant::now)),
+                    if self.startup_deadline.is_some() =>
+                {
+                    let span = span();
+                    let _enter = span.enter();
+                    self.refresh_blocks().await?;
+                }
+            );
+        }
+    }
+
+    /// Take every message already received, so that a burst of reloads is
+    /// handled as a single reload. Other messages are kept to be handled
+    /// next, with any shutdown first, since it takes precedence over
+    /// reloading.
+    ///
+    /// Returns the number of reloads coalesced, and whether a shutdown was
+    /// received.
+    fn coalesce_reloads(&mut self) -> (usize, bool) {
+        let mut coalesced = 0;
+        let mut shut_down = false;
+        while let Ok(msg) = self.rx.try_recv() {
+            match msg {
+                BarMsg::Reload => coalesced += 1,
+                BarMsg::ShutDown => shut_down = true,
+                msg => self.pending.push_back(msg),
+            }
+        }
+        if shut_down {
+            self.pending.push_front(BarMsg::ShutDown);
+        }
+        (coalesced, shut_down)
+    }
+
+    /// Dim the blocks, since the bar is idle.
+    async fn dim_idle(&mut self) -> anyhow::Result<()> {
+        tracing::trace!("idle, dimming blocks");
+        self.dimmed = true;
+        self.force_refresh().await
+    }
+
+    /// Log whether the bar is static, since no block has a command. Such a
+    /// bar sends its blocks once, and then idles until reloaded.
+    fn log_if_static(&self) {
+        if self.blocks.iter().all(BlockHandle::is_static) {
+            tracing::info!("no block has a command, so the bar is static until reloaded");
+        }
+    }
+
+    /// Send the blocks, even if they're unchanged since they were last sent.
+    ///
+    /// # Errors
+    ///
+    /// Writing to the output may fail.
+    pub async fn force_refresh(&mut self) -> anyhow::Result<()> {
+        self.latest_blocks_hash = None;
+        self.refresh_blocks().await
+    }
+
+    /// Reset the idle timer, restoring the bar's colors if it was dimmed.
+    ///
+    /// # Errors
+    ///
+    /// Writing to the output may fail.
+    async fn wake(&mut self) -> anyhow::Result<()> {
+        self.last_activity = time::Instant::now();
+        if self.dimmed {
+            tracing::trace!("no longer idle, restoring colors");
+            self.dimmed = false;
+            self.force_refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns when the bar becomes idle and should be dimmed, unless it
+    /// already is or never will be.
+    fn idle_deadline(&self) -> Option<time::Instant> {
+        if self.dimmed {
+            return None;
+        }
+        let idle_dim = self.idle_dim.as_ref()?;
+        self.last_activity
+            .checked_add(Duration::from_secs_f32(idle_dim.timeout))
+    }
+
+    /// Pass `event` to the block whose `name` and `instance`, as last sent,
+    /// match its own.
+    fn dispatch_click(&self, event: ClickEvent) {
+        let block = self
+            .latest_sent
+            .iter()
+            .find(|(_, name, instance)| *name == event.name && *instance == event.instance)
+            .and_then(|(idx, _, _)| self.blocks.get(*idx));
+        let Some(block) = block else {
+            tracing::debug!(
+                name = event.name.as_deref(),
+                instance = event.instance.as_deref(),
+                "click event matches no block"
+            );
+            return;
+        };
+        block.click(event);
+    }
+
+    /// Returns whether each block should be omitted to keep the total length
+    /// of `full_text` within `max_total_length`. The lowest priority blocks are
+    /// omitted first, and among equal priorities, the rightmost as sent.
+    ///
+    /// If nothing needs to be omitted, the returned vector may be empty.
+    async fn omitted_blocks(&self) -> Vec<bool> {
+        let Some(max_total_length) = self.max_total_length else {
+            return Vec::new();
+        };
+
+        let mut lengths = Vec::with_capacity(self.blocks.len());
+        let mut priorities = Vec::with_capacity(self.blocks.len());
+        for block in self.blocks.iter() {
+            let body = block.body.read().await;
+            lengths.push(if block.hidden.load(Ordering::Relaxed) {
+                0
+            } else {
+                body.full_text
+                    .as_ref()
+                    .map_or(0, |full_text| full_text.chars().count())
+            });
+            priorities.push(block.priority);
+        }
+
+        let mut total: usize = lengths.iter().sum();
+        if total <= max_total_length {
+            return Vec::new();
+        }
+
+        let mut order: Vec<usize> = (0..lengths.len()).collect();
+        let position = |idx: usize| {
+            if self.reverse {
+                lengths.len() - 1 - idx
+            } else {
+                idx
+            }
+        };
+        order.sort_by_key(|&idx| (priorities[idx], Reverse(position(idx))));
+
+        let mut omitted = vec![false; lengths.len()];
+        for idx in order {
+            if total <= max_total_length {
+                break;
+            }
+            omitted[idx] = true;
+            total -= lengths[idx];
+        }
+
+        tracing::debug!(
+            max_total_length,
+            num_omitted = omitted.iter().filter(|omitted| **omitted).count(),
+            "omitting low priority blocks"
+        );
+
+        omitted
+    }
+
     pub async fn listen(mut self) -> anyhow::Result<()> {
         async fn inner(
             span: impl Fn() -> tracing::Span,
             bar: &mut Bar,
             sig_handles: &mut Vec<task::JoinHandle<()>>,
         ) -> anyhow::Result<()> {
-            while let Some(msg) = bar.rx.recv().await {
+            bar.refresh_if_empty().await?;
+            bar.log_if_static();
+            while let Some(msg) = bar.recv(&span).await? {
                 let span = span();
                 let _enter = span.enter();
                 span.record("msg", format_args!("{msg:?}"));
@@ -208,20 +1025,75 @@ impl Bar {
 
                 match msg {
                     BarMsg::Reload => {
-                        tracing::info!("reloading configuration");
-                        bar.reload().await?;
+                        bar.reload_coalesced().await?;
+                        bar.replace_signal_handles(sig_handles).await;
+                    }
+
+                    BarMsg::ConfigChanged => {
+                        bar.config_changed().await?;
+                        bar.replace_signal_handles(sig_handles).await;
+                    }
+
+                    BarMsg::ReloadBlock(name) => {
+                        tracing::info!(name = &*name, "reloading configuration of block");
+                        bar.wake().await?;
+                        if let Err(err) = bar.reload_block(&name).await {
+                            tracing::error!(
+                                err = format_args!("{err:#}"),
+                                "failed to reload block"
+                            );
+                        }
                     }
 
                     BarMsg::ShutDown => {
                         tracing::info!("shutting down");
                         bar.shut_down(sig_handles).await;
+                        bar.run_post_command().await;
                         break;
                     }
 
+                    BarMsg::Stop => tracing::debug!("swaybar stopped processing"),
+
                     BarMsg::RefreshBlocks => {
                         tracing::trace!("refreshing blocks");
                         bar.refresh_blocks().await?;
                     }
+
+                    BarMsg::ToggleLogLevel => {
+                        bar.wake().await?;
+                        bar.toggle_log_level();
+                    }
+
+                    /* after continuing, swaybar is sent the blocks again */
+                    BarMsg::ForceRefresh | BarMsg::Continue => {
+                        tracing::trace!("forcing refresh of blocks");
+                        bar.wake().await?;
+                        bar.force_refresh().await?;
+                    }
+
+                    BarMsg::Pulse => {
+                        tracing::trace!("pulsing refresh of blocks");
+                        bar.force_refresh().await?;
+                    }
+
+                    BarMsg::Activity => {
+                        bar.wake().await?;
+                    }
+
+                    BarMsg::Click(event) => {
+                        bar.wake().await?;
+                        bar.dispatch_click(event);
+                    }
+
+                    BarMsg::ClickEof => {
+                        if bar.click_eof == ClickEofPolicy::Shutdown {
+                            tracing::info!("standard input closed, shutting down");
+                            bar.shut_down(sig_handles).await;
+                            bar.run_post_command().await;
+                            break;
+                        }
+                        tracing::debug!("standard input closed, no longer reading click events");
+                    }
                 }
             }
             Ok(())
@@ -233,79 +1105,414 @@ impl Bar {
             .signal_handles()
             .expect("signal handles must not yet be created");
 
+        self.start_click_reader();
+        let watch_handle = self.config_watch_handle();
+
         let result = inner(span, &mut self, &mut sig_handles).await;
-        match result {
-            Ok(()) => {}
-            Err(ref err) => {
-                let span = span();
-                let _enter = span.enter();
-                tracing::error!(
-                    err = format_args!("{err}"),
-                    "fatal error has occurred, shutting down"
-                );
-                self.shut_down(&mut sig_handles).await;
-            }
+        if let Some(handle) = watch_handle {
+            handle.abort();
+            crate::await_cancellable(handle).await;
+        }
+        if let Err(ref err) = result {
+            let span = span();
+            let _enter = span.enter();
+            tracing::error!(
+                err = format_args!("{err}"),
+                "fatal error has occurred, shutting down"
+            );
+            self.shut_down(&mut sig_handles).await;
         }
         result
     }
 }
 
 impl Bar {
+    /// Returns each signal the bar listens for, along with the message it
+    /// sends to the bar and the name it's logged as.
+    fn signal_actions(&self) -> Vec<(Signal, BarMsg, &'static str)> {
+        let header = self.header;
+        let cont_signal = header.cont_signal.unwrap_or(Header::DEFAULT_CONT_SIG);
+        let stop_signal = header.stop_signal.unwrap_or(Header::DEFAULT_STOP_SIG);
+        let reload_signal = self.reload_signal.unwrap_or(cont_signal);
+        let shutdown_signal = self.shutdown_signal.unwrap_or(stop_signal);
+        let mut actions = vec![
+            (reload_signal, BarMsg::Reload, "reload"),
+            (shutdown_signal, BarMsg::ShutDown, "shutdown"),
+        ];
+        /* the header's signals are what swaybar actually sends, so they're
+         * listened for even if they do nothing else */
+        if cont_signal != reload_signal {
+            actions.push((cont_signal, BarMsg::Continue, "continue"));
+        }
+        if stop_signal != shutdown_signal {
+            actions.push((stop_signal, BarMsg::Stop, "stop"));
+        }
+        actions.extend(
+            self.refresh_signal
+                .map(|signum| (signum, BarMsg::ForceRefresh, "refresh")),
+        );
+        actions.extend(
+            self.log_signal
+                .map(|signum| (signum, BarMsg::ToggleLogLevel, "log")),
+        );
+        actions
+    }
+
     fn signal_handles(&mut self) -> Option<Vec<task::JoinHandle<()>>> {
         self.signal_handles_created.then(|| {
             self.signal_handles_created = true;
-            let mut handles = Vec::with_capacity(2);
-            let header = self.header;
-            for (signum, action, signame) in [
-                (
-                    header.cont_signal.unwrap_or(Header::DEFAULT_CONT_SIG),
-                    BarMsg::Reload,
-                    "continue",
-                ),
-                (
-                    header.stop_signal.unwrap_or(Header::DEFAULT_STOP_SIG),
-                    BarMsg::ShutDown,
-                    "stop",
-                ),
-            ] {
-                let tx = self.tx.clone();
-                let handle = task::spawn(async move {
-                    let span = span!(
-                        Level::INFO,
-                        "sig_listener",
-                        signum = format_args!("{signum}"),
-                        signame = format_args!("{signame}")
-                    );
-
-                    let sig_kind = SignalKind::from_raw(signum.as_raw());
-                    match signal(sig_kind) {
-                        Ok(mut sig) => {
-                            {
-                                let _enter = span.enter();
-                                tracing::trace!("signal is valid, listening");
-                            }
-
-                            while let Some(()) = sig.recv().await {
-                                let _enter = span.enter();
-                                tracing::trace!("received signal, sending {action:?} to Bar");
-                                tx.send(action)
-                                    .await
-                                    .expect("signal handles must outlive Bar");
-                            }
+            self.spawn_signal_listeners()
+        })
+    }
+
+    /// Spawn a task listening for each signal in
+    /// [`signal_actions`](Bar::signal_actions).
+    fn spawn_signal_listeners(&self) -> Vec<task::JoinHandle<()>> {
+        let mut handles = Vec::with_capacity(4);
+        for (signum, action, signame) in self.signal_actions() {
+            let bar = self.handle();
+            let handle = task::spawn(async move {
+                let span = span!(
+                    Level::INFO,
+                    "sig_listener",
+                    signum = format_args!("{signum}"),
+                    signame = format_args!("{signame}")
+                );
+
+                let sig_kind = SignalKind::from_raw(signum.as_raw());
+                match signal(sig_kind) {
+                    Ok(mut sig) => {
+                        {
+                            let _enter = span.enter();
+                            tracing::trace!("signal is valid, listening");
                         }
-                        Err(err) => {
+
+                        loop {
+                            crate::recv_signal(&mut sig, sig_kind).await;
                             let _enter = span.enter();
-                            if signal_hook_registry::FORBIDDEN.contains(&signum.as_raw()) {
-                                tracing::warn!("signal is invalid");
-                            } else {
-                                tracing::error!("failed to register signal listener: {err}");
-                            }
+                            tracing::trace!("received signal, sending {action:?} to Bar");
+                            bar.send(action.clone())
+                                .await
+                                .expect("signal handles must outlive Bar");
                         }
                     }
-                });
-                handles.push(handle);
+                    Err(err) => {
+                        let _enter = span.enter();
+                        if signal_hook_registry::FORBIDDEN.contains(&signum.as_raw()) {
+                            tracing::warn!("signal is invalid");
+                        } else {
+                            tracing::error!("failed to register signal listener: {err}");
+                        }
+                    }
+                }
+            });
+            handles.push(handle);
+        }
+        handles
+    }
+}
+
+/// Prefix `body` with a powerline chevron, blending from `prev_background`
+/// (the background of the block before it) into its own background. Since
+/// this requires Pango markup, the body is escaped if it doesn't use it.
+fn powerline(body: &mut Body, prev_background: Option<&str>) {
+    /* right-aligned chevron from powerline fonts */
+    const CHEVRON: &str = "\u{e0b2}";
+
+    if body.markup != Some(Markup::Pango) {
+        for text in [&mut body.full_text, &mut body.short_text]
+            .into_iter()
+            .flatten()
+        {
+            *text = protocol::escape_markup(text).into();
+        }
+        body.markup = Some(Markup::Pango);
+    }
+
+    let foreground = body
+        .background
+        .as_ref()
+        .map(|background| format!(r#" foreground="{background}""#))
+        .unwrap_or_default();
+    let background = prev_background
+        .map(|background| format!(r#" background="{background}""#))
+        .unwrap_or_default();
+    let chevron = format!("<span{foreground}{background}>{CHEVRON}</span>");
+    for text in [&mut body.full_text, &mut body.short_text]
+        .into_iter()
+        .flatten()
+    {
+        text.insert_str(0, &chevron);
+    }
+
+    body.separator = Some(false);
+    body.separator_block_width = Some(0);
+}
+
+/// Multiply the alpha of every color in `body` by `opacity`. Colors which
+/// aren't valid are left alone.
+fn dim(body: &mut Body, opacity: f32) {
+    for color in [&mut body.color, &mut body.background, &mut body.border]
+        .into_iter()
+        .flatten()
+    {
+        if !protocol::is_valid_color(color) {
+            continue;
+        }
+        let (rgb, alpha) = color[1..].split_at(6);
+        let alpha = u8::from_str_radix(alpha, 16).unwrap_or(u8::MAX);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let alpha = (f32::from(alpha) * opacity).round() as u8;
+        *color = format!("#{rgb}{alpha:02X}").into();
+    }
+}
+
+/// Open the audit log at `path`, if configured. Failure to open it is logged
+/// as a warning, and commands are run without being recorded.
+fn open_audit_log(path: Option<&Path>) -> Option<Arc<AuditLog>> {
+    let path = path?;
+    match AuditLog::open(path) {
+        Ok(log) => {
+            tracing::debug!(
+                path = format_args!(r#""{}""#, path.display()),
+                "opened audit log"
+            );
+            Some(log)
+        }
+        Err(err) => {
+            tracing::warn!(
+                path = format_args!(r#""{}""#, path.display()),
+                err = format_args!("{err}"),
+                "failed to open audit log"
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use std::{env, fs, process};
+
+    /// Output whose contents can be checked while the bar still owns it
+    #[derive(Clone, Debug, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
+        }
+    }
+
+    const HEADER: &str = concat!(
+        r#"{"version":1,"click_events":false,"#,
+        r#""cont_signal":"SIGCONT","stop_signal":"SIGSTOP"}"#,
+        "\n[",
+    );
+
+    /// Returns a bar configured by `utf8` whose blocks are all initialized,
+    /// and its output.
+    async fn bar(utf8: &str) -> (Bar, Shared) {
+        let toml = toml::from_str(utf8).unwrap();
+        let config = Config::new(toml, "<test>".into(), env::temp_dir()).unwrap();
+        let out = Shared::default();
+        let bar = Bar::with_writer(config, out.clone());
+        /* let blocks without a command show their configured text */
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(bar.blocks.all_initialized());
+        (bar, out)
+    }
+
+    /// Send the blocks again, even if they haven't changed.
+    async fn refresh_again(bar: &mut Bar) {
+        bar.latest_blocks_hash = None;
+        bar.refresh_blocks().await.unwrap();
+    }
+
+    /// Assert that `stream` is a header line followed by a prefix of an
+    /// infinite array of arrays, which is closed by appending `[]]`.
+    fn assert_well_formed(stream: &str) {
+        let (header, body) = stream.split_once('\n').unwrap();
+        serde_json::from_str::<serde_json::Value>(header).unwrap();
+        assert!(body.starts_with('['), "{body:?}");
+        assert!(body.ends_with("],\n"), "{body:?}");
+        let closed = format!("{body}[]]");
+        serde_json::from_str::<Vec<Vec<serde_json::Value>>>(&closed).unwrap();
+    }
+
+    #[tokio::test]
+    async fn header_then_arrays() {
+        let (mut bar, out) = bar(concat!(
+            "[[block]]\nfull_text = \"a\"\n",
+            "[[block]]\nfull_text = \"b\"\n",
+        ))
+        .await;
+        bar.write_header().unwrap();
+        assert_eq!(out.take(), HEADER);
+        bar.refresh_blocks().await.unwrap();
+        refresh_again(&mut bar).await;
+        let body = out.take();
+        assert_eq!(
+            body,
+            concat!(
+                r#"[{"full_text":"a"},"#,
+                "\n",
+                r#"{"full_text":"b"}],"#,
+                "\n",
+                r#"[{"full_text":"a"},"#,
+                "\n",
+                r#"{"full_text":"b"}],"#,
+                "\n",
+            )
+        );
+        assert_well_formed(&format!("{HEADER}{body}"));
+    }
+
+    #[tokio::test]
+    async fn unchanged_blocks_are_not_sent() {
+        let (mut bar, out) = bar("[[block]]\nfull_text = \"a\"").await;
+        bar.write_header().unwrap();
+        assert_eq!(out.take(), HEADER);
+        bar.refresh_blocks().await.unwrap();
+        bar.refresh_blocks().await.unwrap();
+        assert_eq!(out.take(), "[{\"full_text\":\"a\"}],\n");
+    }
+
+    #[tokio::test]
+    async fn single_block() {
+        let (mut bar, out) = bar("[[block]]\nfull_text = \"a\"").await;
+        bar.write_header().unwrap();
+        assert_eq!(out.take(), HEADER);
+        bar.refresh_blocks().await.unwrap();
+        refresh_again(&mut bar).await;
+        let body = out.take();
+        assert_eq!(body, "[{\"full_text\":\"a\"}],\n[{\"full_text\":\"a\"}],\n");
+        assert_well_formed(&format!("{HEADER}{body}"));
+    }
+
+    #[tokio::test]
+    async fn empty_blocks() {
+        let (mut bar, out) = bar("").await;
+        bar.write_header().unwrap();
+        assert_eq!(out.take(), HEADER);
+        bar.refresh_blocks().await.unwrap();
+        refresh_again(&mut bar).await;
+        let body = out.take();
+        assert_eq!(body, "[],\n[],\n");
+        assert_well_formed(&format!("{HEADER}{body}"));
+    }
+
+    #[tokio::test]
+    async fn hidden_blocks_leave_no_stray_commas() {
+        let (mut bar, out) = bar(concat!(
+            "[[block]]\nfull_text = \"a\"\n",
+            "[[block]]\nfull_text = \"b\"\n",
+            "[[block]]\nfull_text = \"c\"\n",
+        ))
+        .await;
+        bar.write_header().unwrap();
+        assert_eq!(out.take(), HEADER);
+        for idx in [0, 2] {
+            let block = bar.blocks.get(idx).unwrap();
+            block.hidden.store(true, Ordering::Relaxed);
+        }
+        bar.refresh_blocks().await.unwrap();
+        let body = out.take();
+        assert_eq!(body, "[{\"full_text\":\"b\"}],\n");
+        assert_well_formed(&format!("{HEADER}{body}"));
+    }
+
+    /// Send `bar` the message it sends itself when it receives `signum`, as
+    /// if the signal were delivered.
+    async fn simulate_signal(bar: &BarHandle, actions: &[(Signal, BarMsg)], signum: Signal) {
+        let (_, msg) = actions
+            .iter()
+            .find(|(sig, _)| *sig == signum)
+            .expect("bar must listen for signal");
+        bar.send(msg.clone()).await.unwrap();
+    }
+
+    /// Wait until `out` has received something containing `expected`.
+    async fn wait_for(out: &Shared, expected: &str) {
+        let mut received = String::new();
+        time::timeout(Duration::from_secs(5), async {
+            while !received.contains(expected) {
+                received.push_str(&out.take());
+                time::sleep(Duration::from_millis(10)).await;
             }
-            handles
         })
+        .await
+        .unwrap_or_else(|_| panic!("expected {expected:?}, received {received:?}"));
+    }
+
+    #[tokio::test]
+    async fn cont_reloads_and_stop_shuts_down() {
+        let dir = env::temp_dir().join(format!("smolbar-test-signals-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[[block]]\nfull_text = \"one\"").unwrap();
+
+        let config = Config::read_from_path(&path, true, None).unwrap();
+        let out = Shared::default();
+        let mut bar = Bar::with_writer(config, out.clone());
+        let handle = bar.handle();
+        let actions: Vec<(Signal, BarMsg)> = bar
+            .signal_actions()
+            .into_iter()
+            .map(|(signum, msg, _)| (signum, msg))
+            .collect();
+        bar.write_header().unwrap();
+
+        let simulate = async {
+            wait_for(&out, "one").await;
+            fs::write(&path, "[[block]]\nfull_text = \"two\"").unwrap();
+            simulate_signal(&handle, &actions, Header::DEFAULT_CONT_SIG).await;
+            wait_for(&out, "two").await;
+            simulate_signal(&handle, &actions, Header::DEFAULT_STOP_SIG).await;
+        };
+        let (listened, ()) = tokio::join!(
+            time::timeout(Duration::from_secs(5), bar.listen()),
+            simulate
+        );
+        listened.expect("bar must shut down").unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A configuration parsed from a string is parsed again when reloaded,
+    /// rather than read from a file which doesn't exist.
+    #[tokio::test]
+    async fn reload_string_config() {
+        let config = Config::from_str("[[block]]\nfull_text = \"a\"", &env::temp_dir()).unwrap();
+        let out = Shared::default();
+        let mut bar = Bar::with_writer(config, out.clone());
+        let handle = bar.handle();
+        bar.write_header().unwrap();
+
+        let simulate = async {
+            wait_for(&out, "\"a\"").await;
+            handle.send(BarMsg::Reload).await.unwrap();
+            handle.send(BarMsg::ShutDown).await.unwrap();
+        };
+        let (listened, ()) = tokio::join!(
+            time::timeout(Duration::from_secs(5), bar.listen()),
+            simulate
+        );
+        listened.expect("bar must shut down").unwrap();
     }
 }
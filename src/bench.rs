@@ -0,0 +1,66 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use anyhow::Context;
+use tokio::time;
+
+use core::fmt::Write as FmtWrite;
+use core::time::Duration;
+use std::env;
+use std::io::{self, Write};
+use std::time::Instant;
+
+use crate::bar::Bar;
+use crate::config::Config;
+
+/// Number of refreshes measured per case.
+const ITERATIONS: u32 = 1000;
+
+/// Time given to blocks to set their initial bodies before measuring.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Measure the time [`Bar::refresh_blocks`] takes with `num_blocks` static
+/// blocks, both when the blocks are sent and when the refresh is suppressed
+/// because they're unchanged. The bar writes to a sink, and the results are
+/// written to `out`.
+///
+/// Logging is included in the measurement, so this is best run with
+/// `--terse`.
+///
+/// # Errors
+///
+/// - Getting the current directory may fail
+/// - Parsing or validating the generated configuration may fail
+/// - Writing to `out` may fail
+pub async fn refresh<W: Write>(num_blocks: usize, mut out: W) -> anyhow::Result<()> {
+    let mut toml = String::new();
+    for idx in 0..num_blocks {
+        writeln!(toml, "[[block]]\nfull_text = \"block {idx}\"")?;
+    }
+    let config = Config::from_str(&toml, &env::current_dir()?)
+        .context("failed to load generated configuration")?;
+    let mut bar = Bar::with_writer(config, io::sink());
+
+    /* blocks without commands set their bodies as soon as they start, so
+     * they only need a moment */
+    time::sleep(SETTLE_TIME).await;
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        bar.force_refresh().await?;
+    }
+    let sent = start.elapsed() / ITERATIONS;
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        bar.refresh_blocks().await?;
+    }
+    let suppressed = start.elapsed() / ITERATIONS;
+
+    writeln!(out, "blocks: {num_blocks}")?;
+    writeln!(out, "iterations: {ITERATIONS}")?;
+    writeln!(out, "sent: {sent:?} per refresh")?;
+    writeln!(out, "suppressed: {suppressed:?} per refresh")?;
+
+    Ok(())
+}
@@ -1,7 +1,10 @@
 // copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
 // licensed under GPL-3.0-or-later
 
-use tokio::process::Command;
+use cowstr::CowStr;
+use serde_json::ser;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStderr, ChildStdout, Command};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{mpsc, RwLock};
 use tokio::task::JoinHandle;
@@ -9,15 +12,28 @@ use tokio::{task, time};
 use tokio_util::sync::CancellationToken;
 use tracing::{field, span, Level};
 
+use alloc::borrow::Cow;
+use alloc::collections::{BTreeSet, VecDeque};
 use alloc::sync::Arc;
-use core::str::{self, FromStr, Lines};
+use core::mem;
+use core::ops::Range;
+use core::pin::Pin;
+use core::str::{self, FromStr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
 use core::time::Duration;
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::string::FromUtf8Error;
+use std::time::{Instant, SystemTime};
 
+use crate::audit::{self, AuditLog};
 use crate::bar::BarMsg;
-use crate::config::TomlBlock;
-use crate::protocol::Body;
+use crate::config::{Capture, Shell, TomlBlock, TrendPosition, Umask, Watch};
+use crate::pidfile;
+use crate::protocol::{self, Body, ClickEvent, Markup};
+use crate::watch::FileWatcher;
 use crate::Hash;
 
 #[allow(clippy::module_name_repetitions)]
@@ -26,54 +42,283 @@ pub struct RegenBody {
     init: bool,
 }
 
+/// Delivers a [`Block`]'s signal to it, doing exactly what receiving the
+/// signal would.
+#[derive(Clone, Debug)]
+pub struct Signaller {
+    tx: mpsc::Sender<RegenBody>,
+    bar_tx: mpsc::Sender<BarMsg>,
+}
+
+impl Signaller {
+    /// Request the block regenerate its body, and tell the bar about the
+    /// activity.
+    pub async fn signal(&self) {
+        self.tx
+            .send(RegenBody { init: false })
+            .await
+            .expect("Block must outlive signal handle");
+        self.bar_tx
+            .send(BarMsg::Activity)
+            .await
+            .expect("Bar must outlive its Blocks");
+    }
+}
+
+/// Numbers recorded from a block's output, for displaying how they changed.
+#[derive(Debug, Default)]
+struct History {
+    /// Recent values displayed by `sparkline`, oldest first
+    samples: VecDeque<f64>,
+    /// Previous and latest value compared by `trend`
+    trend: Option<(f64, f64)>,
+    /// Placeholders of `format` which were warned about for having no
+    /// value, so that they're only warned about once
+    missing: BTreeSet<String>,
+}
+
+/// Configuration shared by all blocks.
+#[derive(Debug)]
+pub struct Global {
+    /// [`Body`] configured at `global` scope
+    pub body: Body,
+    /// Path to execute block commands in, unless overridden by the block
+    pub command_dir: Arc<PathBuf>,
+    /// Environment variables set for block commands
+    pub env: Vec<(String, String)>,
+    /// Log of executed block commands, if enabled
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Umask of block commands, unless overridden by the block
+    pub umask: Option<Umask>,
+    /// Shell through which block commands are executed, unless overridden
+    /// by the block
+    pub shell: Shell,
+}
+
+/// New configuration for a running [`Block`], applied without restarting it.
+#[derive(Debug)]
+pub struct Reconfigure {
+    pub toml: TomlBlock,
+    pub global: Arc<Global>,
+    /// Whether to execute the command again, rather than updating the body
+    /// from its latest output
+    pub rerun: bool,
+}
+
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Debug)]
 pub struct Block {
     body: Arc<RwLock<Body>>,
-    global_body: Arc<Body>,
+    global: Arc<Global>,
     toml: TomlBlock,
     command_dir: Arc<PathBuf>,
+    /// Output of the latest command, if it's been executed
+    last_immediate: Option<String>,
+    /// Whether the latest command failed
+    failed: bool,
+    /// Whether the latest command succeeded without printing anything
+    printed_nothing: bool,
+    /// Numbers recently recorded from the command's output
+    history: History,
+    /// Whether the block is hidden, because its command printed nothing
+    hidden: Arc<AtomicBool>,
+    /// Whether the block's command has finished for the first time, or will
+    /// never run on start
+    initialized: Arc<AtomicBool>,
 
     id: usize,
 
     rx: mpsc::Receiver<RegenBody>,
     tx: mpsc::Sender<RegenBody>,
+    reconfigure_rx: mpsc::Receiver<Reconfigure>,
+    reconfigure_tx: mpsc::Sender<Reconfigure>,
+    click_rx: mpsc::Receiver<ClickEvent>,
+    click_tx: mpsc::Sender<ClickEvent>,
     bar_tx: mpsc::Sender<BarMsg>,
     cancel: CancellationToken,
 
+    /// Running command, if `persistent` is set
+    persistent: Option<Persistent>,
+    /// When to start the command again after it exited, if `persistent` is
+    /// set
+    restart_at: Option<time::Instant>,
+    /// How long to wait before starting the command again the next time it
+    /// exits, if `persistent` is set
+    restart_delay: Duration,
+
+    /// Whether `prlimit(1)` can set the resource limits, as checked by
+    /// [`Block::check_rlimits`]
+    rlimits_settable: bool,
+
     interval_handle_created: bool,
     signal_handle_created: bool,
+    watch_handle_created: bool,
+}
+
+/// Command of a block with `persistent` set, which keeps running.
+#[derive(Debug)]
+struct Persistent {
+    child: Child,
+    /// Output stream the body is read from
+    reader: BufReader<Pipe>,
+    /// Bytes of the line being read, kept across cancellation
+    line: Vec<u8>,
+    /// Whether invalid UTF-8 in the output is replaced, rather than an error
+    lossy: bool,
+    /// Lines read since the latest body
+    group: String,
+    /// Whether the next body initializes the block
+    init: bool,
+    /// Whether the command has written a body since it started
+    wrote_body: bool,
+    /// Path recorded in the audit log
+    audited: PathBuf,
+    spawned_at: SystemTime,
+    start: Instant,
+}
+
+impl Persistent {
+    /// Returns the next body the command writes, or `None` once it closes
+    /// its output. Each body is ended by an empty line, and empty lines
+    /// before it are ignored.
+    ///
+    /// This is cancel safe, so no output is lost if it's not awaited to
+    /// completion.
+    async fn next_body(&mut self) -> Option<String> {
+        loop {
+            match self.reader.read_until(b'\n', &mut self.line).await {
+                /* a final line without a newline is still a line */
+                Ok(0) if self.line.is_empty() => break,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "failed to read command output");
+                    break;
+                }
+            }
+            let mut bytes = mem::take(&mut self.line);
+            if bytes.last() == Some(&b'\n') {
+                bytes.pop();
+                if bytes.last() == Some(&b'\r') {
+                    bytes.pop();
+                }
+            }
+            let line = match decode_output(bytes, self.lossy) {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "command produced invalid utf8");
+                    break;
+                }
+            };
+            if line.is_empty() {
+                if !self.group.is_empty() {
+                    self.wrote_body = true;
+                    return Some(mem::take(&mut self.group));
+                }
+            } else {
+                self.group.push_str(&line);
+                self.group.push('\n');
+            }
+        }
+        /* a body cut off by the command exiting is still shown */
+        (!self.group.is_empty()).then(|| {
+            self.wrote_body = true;
+            mem::take(&mut self.group)
+        })
+    }
+}
+
+/// Output stream of a command.
+#[derive(Debug)]
+enum Pipe {
+    Stdout(ChildStdout),
+    Stderr(ChildStderr),
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Stdout(stdout) => Pin::new(stdout).poll_read(cx, buf),
+            Self::Stderr(stderr) => Pin::new(stderr).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Returns the next body written by `persistent`, if any. If there's no
+/// command running, this never completes.
+async fn next_body(persistent: &mut Option<Persistent>) -> Option<String> {
+    match persistent {
+        Some(persistent) => persistent.next_body().await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Sleep until `deadline`, or forever if there's none.
+async fn sleep_until(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => core::future::pending().await,
+    }
 }
 
 impl Block {
+    /// Shortest time to wait before starting a `persistent` command again
+    const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+    /// Longest time to wait before starting a `persistent` command again
+    const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
     pub fn new(
         toml: TomlBlock,
-        global_body: Arc<Body>,
-        command_dir: Arc<PathBuf>,
+        global: Arc<Global>,
         bar_tx: mpsc::Sender<BarMsg>,
         id: usize,
         num_blocks: usize,
     ) -> (Self, CancellationToken) {
         let body = Arc::new(RwLock::new(Body::new()));
+        let initialized = toml.command.is_none() || !toml.run_on_start;
         let (tx, rx) = mpsc::channel(
             /* kinda arbitrary. this number tries to prevent hanging if a lot of
              * blocks send a refresh request. */
             num_blocks.saturating_mul(2),
         );
+        let (reconfigure_tx, reconfigure_rx) = mpsc::channel(1);
+        /* a few clicks may queue up while the block is busy. beyond that,
+         * they're dropped rather than handled long after the fact. */
+        let (click_tx, click_rx) = mpsc::channel(4);
         let cancel_parent = CancellationToken::new();
         let cancel_child = cancel_parent.child_token();
+        let command_dir = Self::command_dir(&toml, &global);
         (
             Self {
                 body,
-                global_body,
+                global,
                 toml,
                 command_dir,
+                last_immediate: None,
+                failed: false,
+                printed_nothing: false,
+                history: History::default(),
+                hidden: Arc::new(AtomicBool::new(false)),
+                initialized: Arc::new(AtomicBool::new(initialized)),
                 id,
                 rx,
                 tx,
+                reconfigure_rx,
+                reconfigure_tx,
+                click_rx,
+                click_tx,
                 bar_tx,
                 cancel: cancel_child,
+                persistent: None,
+                restart_at: None,
+                restart_delay: Self::MIN_RESTART_DELAY,
+                rlimits_settable: true,
                 interval_handle_created: false,
                 signal_handle_created: false,
+                watch_handle_created: false,
             },
             cancel_parent,
         )
@@ -83,21 +328,103 @@ impl Block {
         Arc::clone(&self.body)
     }
 
+    /// Returns whether the block is hidden, which is the case if its command
+    /// succeeded without printing anything, unless `keep_empty` is set.
+    pub fn hidden(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.hidden)
+    }
+
+    /// Returns whether the block is initialized, which is the case once its
+    /// command first finishes, or right away if it has none or doesn't run on
+    /// start.
+    pub fn initialized(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.initialized)
+    }
+
+    /// Returns a [`Signaller`] which acts as if the block received its signal.
+    pub fn signaller(&self) -> Signaller {
+        Signaller {
+            tx: self.tx.clone(),
+            bar_tx: self.bar_tx.clone(),
+        }
+    }
+
+    /// Returns a sender through which the block may be reconfigured while
+    /// it's running.
+    pub fn reconfigurer(&self) -> mpsc::Sender<Reconfigure> {
+        self.reconfigure_tx.clone()
+    }
+
+    /// Returns a sender through which click events may be passed to the
+    /// block.
+    pub fn clicker(&self) -> mpsc::Sender<ClickEvent> {
+        self.click_tx.clone()
+    }
+
+    /// Returns whether a block configured as `old` may be reconfigured as
+    /// `new` without restarting it. This is the case unless its command or
+    /// what triggers it changed.
+    pub fn can_reconfigure(old: &TomlBlock, new: &TomlBlock) -> bool {
+        old.command == new.command
+            && old.shell == new.shell
+            && old.interval == new.interval
+            && old.precise_interval == new.precise_interval
+            && old.signal == new.signal
+            && old.watch == new.watch
+            && old.persistent == new.persistent
+    }
+
+    fn command_dir(toml: &TomlBlock, global: &Global) -> Arc<PathBuf> {
+        toml.command_dir
+            .clone()
+            .unwrap_or_else(|| Arc::clone(&global.command_dir))
+    }
+
     pub async fn listen(mut self) {
+        self.check_rlimits().await;
         let interval_handle = self
             .interval_handle()
             .expect("interval handle must not yet be created");
         let signal_handle = self
             .signal_handle()
             .expect("signal handle must not yet be created");
+        let watch_handle = self
+            .watch_handle()
+            .expect("watch handle must not yet be created");
+
+        /* show the configured text right away as a placeholder, rather than
+         * leaving the block blank until its command first finishes. blocks
+         * without a command are initialized to this same body, so no
+         * redundant refresh is requested for them. */
+        {
+            let span = span!(Level::INFO, "block_placeholder", id = self.id);
+            let _enter = span.enter();
+            let changed = Self::update_body(
+                "",
+                false,
+                &self.global.body,
+                &self.toml,
+                &mut *self.body.write().await,
+                &mut self.history,
+                false,
+            );
+            if changed {
+                self.request_refresh().await;
+            }
+        }
 
         // generate body for the first time
         let tx = self.tx.clone();
+        let run_on_start = self.toml.run_on_start;
         task::spawn(async move {
             let span = span!(Level::INFO, "block_init", id = self.id);
             let _enter = span.enter();
-            tracing::trace!("performing body initialization");
-            tx.send(RegenBody { init: true }).await.unwrap();
+            if run_on_start {
+                tracing::trace!("performing body initialization");
+                tx.send(RegenBody { init: true }).await.unwrap();
+            } else {
+                tracing::trace!("run_on_start is disabled, skipping body initialization");
+            }
         });
 
         'listen_loop: loop {
@@ -112,10 +439,11 @@ impl Block {
                 () = self.cancel.cancelled() => {
                     let _enter = span.enter();
                     tracing::trace!("shutting down");
-                    for handle in [interval_handle, signal_handle] {
+                    for handle in [interval_handle, signal_handle, watch_handle] {
                         handle.abort();
                         crate::await_cancellable(handle).await;
                     }
+                    self.stop_persistent("cancelled").await;
                     break 'listen_loop;
                 }
 
@@ -125,7 +453,36 @@ impl Block {
                         span.record("msg", format_args!("{msg:?}"));
                         tracing::trace!("regenerating body");
                     }
-                    self.regenerate_body(msg.init).await;
+                    self.refresh(msg.init, None).await;
+                }
+
+                body = next_body(&mut self.persistent) => {
+                    let _enter = span.enter();
+                    self.persistent_wrote(body).await;
+                }
+
+                () = sleep_until(self.restart_at) => {
+                    let _enter = span.enter();
+                    tracing::trace!("restarting command");
+                    self.restart_at = None;
+                    self.start_persistent(false, None).await;
+                }
+
+                Some(reconfigure) = self.reconfigure_rx.recv() => {
+                    {
+                        let _enter = span.enter();
+                        tracing::trace!("applying new configuration");
+                    }
+                    self.reconfigure(reconfigure).await;
+                }
+
+                Some(event) = self.click_rx.recv() => {
+                    {
+                        let _enter = span.enter();
+                        span.record("msg", format_args!("click with button {}", event.button));
+                        tracing::trace!("handling click");
+                    }
+                    self.click(event).await;
                 }
             );
         }
@@ -133,14 +490,41 @@ impl Block {
 }
 
 impl Block {
+    /// Returns the body of a block configured as `local`, whose command
+    /// printed `immediate`, as if it were its first output.
+    pub fn body_from_output(immediate: &str, global: &Body, local: &TomlBlock) -> Body {
+        let mut body = Body::new();
+        Self::update_body(
+            immediate,
+            false,
+            global,
+            local,
+            &mut body,
+            &mut History::default(),
+            true,
+        );
+        body
+    }
+
+    /// Update `body` from the command output `immediate`, returning whether
+    /// it changed, in which case the bar should be refreshed. `failed` is
+    /// whether the command which printed it failed.
+    ///
+    /// If `record` is true, `immediate` is new output, so the number it
+    /// starts with is recorded in `history` for `sparkline` and `trend`.
+    ///
+    /// This doesn't request the refresh itself, since `body` is locked for
+    /// as long as it's borrowed.
     #[allow(clippy::too_many_lines)]
-    async fn update_body(
-        immediate: Lines<'_>,
+    fn update_body(
+        immediate: &str,
+        failed: bool,
         global: &Body,
         local: &TomlBlock,
         body: &mut Body,
-        bar_tx: mpsc::Sender<BarMsg>,
-    ) {
+        history: &mut History,
+        record: bool,
+    ) -> bool {
         fn update<T: Clone + FromStr>(
             field: &mut Option<T>,
             immediate: Option<&str>,
@@ -148,10 +532,7 @@ impl Block {
             global: Option<&T>,
         ) {
             *field = match immediate {
-                Some(val) => match val.parse() {
-                    Ok(new) => Some(new),
-                    Err(_) => None,
-                },
+                Some(val) => val.parse().ok(),
                 None => None,
             }
             .or_else(|| local.cloned())
@@ -159,14 +540,87 @@ impl Block {
         }
 
         // compute hash of old body to later compare with new body
-        let old_body_hash = crate::Hash::new(body);
+        let hash = |body: &Body| {
+            local
+                .significant_fields
+                .as_ref()
+                .map_or_else(|| Hash::new(body), |fields| Hash::of_fields(body, fields))
+        };
+        let old_body_hash = hash(body);
 
-        let mut lines = immediate;
         let toml = local;
+        let immediate = if toml.trim_blank_lines {
+            trim_blank_lines(immediate)
+        } else {
+            immediate
+        };
+        let mut lines = immediate.lines();
+
+        /* full_text may span multiple lines, up until a line which is exactly
+         * `full_text_end`. otherwise, like all other fields, it's one line. */
+        /* the command's output may contain characters which are special in
+         * pango markup. only the output is escaped, before it fills in
+         * format, so anything configured, like format or fallback_text in
+         * place of the output, is left as written. */
+        let escape_output = toml.escape_pango && !(failed && toml.fallback_text.is_some());
+
+        let multiline_full_text;
+        let formatted;
+        let escaped_values: Vec<(&str, String)>;
+        let full_text_immediate = if let Some(ref format) = toml.format {
+            /* with a format, every line is a named value filling it in, so
+             * no lines are left for other properties, like markup */
+            let values = lines
+                .by_ref()
+                .filter_map(|line| line.split_once('='))
+                .map(|(name, value)| (name.trim(), value.trim()));
+            let values =
+                if escape_output && toml.body.markup.or(global.markup) == Some(Markup::Pango) {
+                    escaped_values = values
+                        .map(|(name, value)| (name, protocol::escape_markup(value)))
+                        .collect();
+                    escaped_values
+                        .iter()
+                        .map(|(name, value)| (*name, value.as_str()))
+                        .collect()
+                } else {
+                    values.collect()
+                };
+            /* only new output is warned about, rather than the placeholder
+             * before the command first runs */
+            let missing = &mut history.missing;
+            formatted = format.render(&values, |name| {
+                if record && !missing.contains(name) {
+                    tracing::warn!(
+                        placeholder = name,
+                        "format placeholder has no value, leaving it empty"
+                    );
+                    missing.insert(name.to_owned());
+                }
+            });
+            Some(formatted.as_str())
+        } else if let Some(ref end) = toml.full_text_end {
+            let mut full_text: Option<String> = None;
+            for line in lines.by_ref() {
+                if line == &**end {
+                    break;
+                }
+                if let Some(ref mut full_text) = full_text {
+                    full_text.push('\n');
+                    full_text.push_str(line);
+                } else {
+                    full_text = Some(line.to_string());
+                }
+            }
+            multiline_full_text = full_text;
+            multiline_full_text.as_deref()
+        } else {
+            lines.next()
+        };
 
         update(
             &mut body.full_text,
-            lines.next(),
+            full_text_immediate,
             toml.body.full_text.as_ref(),
             global.full_text.as_ref(),
         );
@@ -178,19 +632,19 @@ impl Block {
         );
         update(
             &mut body.color,
-            lines.next(),
+            color_immediate("color", lines.next()),
             toml.body.color.as_ref(),
             global.color.as_ref(),
         );
         update(
             &mut body.background,
-            lines.next(),
+            color_immediate("background", lines.next()),
             toml.body.background.as_ref(),
             global.background.as_ref(),
         );
         update(
             &mut body.border,
-            lines.next(),
+            color_immediate("border", lines.next()),
             toml.body.border.as_ref(),
             global.border.as_ref(),
         );
@@ -267,34 +721,459 @@ impl Block {
             global.markup.as_ref(),
         );
 
+        /* without a format, full_text is the output as is, so it's escaped
+         * once markup is known, before applying anything configured */
+        if escape_output
+            && toml.format.is_none()
+            && full_text_immediate.is_some()
+            && body.markup == Some(Markup::Pango)
+        {
+            if let Some(ref mut full_text) = body.full_text {
+                *full_text = protocol::escape_markup(full_text).into();
+            }
+        }
+
+        /* urgent and color may be set by thresholds on the number full_text
+         * starts with. this happens before applying prefix and postfix, so
+         * that the number is actually at the start. */
+        let value = body.full_text.as_deref().and_then(leading_number);
+        if failed && toml.urgent_on_error {
+            body.urgent = Some(true);
+        }
+        if let Some(ref urgent_when) = toml.urgent_when {
+            if value.map_or(false, |value| urgent_when.is_crossed(value)) {
+                body.urgent = Some(true);
+            }
+        }
+        if let Some(value) = value {
+            if let Some(range) = toml.color_when.iter().find(|range| range.contains(value)) {
+                body.color = Some(range.color.clone());
+            }
+        }
+
+        /* full_text may be replaced by a sparkline of recent values. output
+         * which doesn't start with a number isn't recorded, and until any
+         * value is recorded, full_text is left alone. */
+        if let Some(ref sparkline) = toml.sparkline {
+            let samples = &mut history.samples;
+            if let (true, Some(value)) = (record, value) {
+                samples.push_back(value);
+            }
+            while samples.len() > sparkline.samples {
+                samples.pop_front();
+            }
+            if !samples.is_empty() {
+                body.full_text = Some(sparkline.render(samples.iter()).into());
+            }
+        }
+
+        /* the number full_text starts with may be formatted with units. this
+         * happens after thresholds, so that they compare the raw number. */
+        if let Some(scale) = toml.scale {
+            if let Some(ref mut full_text) = body.full_text {
+                map_leading_number(full_text, |number| {
+                    number.parse().map_or_else(
+                        |_| number.to_owned(),
+                        |value| scale.format(value, toml.scale_precision),
+                    )
+                });
+            }
+        }
+
+        /* the number full_text starts with may be padded, so that the block
+         * doesn't change width along with the number of digits */
+        if let Some(ref pad) = toml.pad {
+            if let Some(ref mut full_text) = body.full_text {
+                map_leading_number(full_text, |number| pad.pad(number));
+            }
+        }
+
+        /* full_text may be marked by whether its number rose or fell since the
+         * previous output. output which doesn't start with a number isn't
+         * recorded, and until any number is recorded, it isn't marked. */
+        if let Some(ref trend) = toml.trend {
+            if let (true, Some(value)) = (record, value) {
+                let previous = history.trend.map_or(value, |(_, latest)| latest);
+                history.trend = Some((previous, value));
+            }
+            if let (Some((previous, latest)), Some(ref mut full_text)) =
+                (history.trend, &mut body.full_text)
+            {
+                let marker = trend.marker(previous, latest);
+                match trend.position {
+                    TrendPosition::Before => full_text.insert_str(0, marker),
+                    TrendPosition::After => full_text.push_str(marker),
+                }
+            }
+        }
+
         /* full text is prefixed by `prefix`, postfixed by `postfix` field in
-         * toml */
-        if let Some(ref mut full_text) = body.full_text {
+         * toml. if there's no text but an icon, the icon is shown alone. */
+        let icon_only =
+            toml.icon.is_some() && body.full_text.as_deref().map_or(true, str::is_empty);
+        if let (Some(ref mut full_text), false) = (&mut body.full_text, icon_only) {
             if let Some(ref prefix) = toml.prefix {
                 full_text.insert_str(0, prefix);
             }
+            if let Some(ref postfix) = toml.postfix {
+                full_text.push_str(postfix);
+            }
+        }
+
+        /* the icon goes before everything else, and is shown even if there's
+         * no other text */
+        if let Some(ref icon) = toml.icon {
+            body.full_text
+                .get_or_insert_with(CowStr::new)
+                .insert_str(0, icon);
+        }
+
+        /* icons which aren't in the expected range would silently render as
+         * missing glyphs, so warn about them */
+        if let Some(range) = toml.expect_glyph_in {
+            if let Some(first) = body
+                .full_text
+                .as_deref()
+                .and_then(|text| text.chars().next())
+            {
+                if !range.contains(first) {
+                    tracing::warn!(
+                        glyph = format_args!("U+{:04X}", u32::from(first)),
+                        expected = format_args!("{}", String::from(range)),
+                        "full_text starts with a character outside expect_glyph_in"
+                    );
+                }
+            }
         }
 
+        /* full text is truncated to `max_length` characters. this happens
+         * after applying prefix and postfix, so the limit applies to exactly
+         * what is displayed. with pango markup, only the displayed characters
+         * count, and the markup around them is kept intact. */
+        let pango = body.markup == Some(Markup::Pango);
         if let Some(ref mut full_text) = body.full_text {
-            if let Some(ref postfix) = toml.postfix {
-                full_text.push_str(postfix);
+            if let Some(max_length) = toml.max_length {
+                truncate(full_text, max_length, ellipsis(toml), pango);
             }
-        };
+        }
+
+        /* if no scope defined short_text, it may be derived from full_text */
+        if body.short_text.is_none() {
+            if let Some(ref auto_short) = toml.auto_short {
+                if let Some(ref full_text) = body.full_text {
+                    let mut short_text = full_text.clone();
+                    truncate(&mut short_text, auto_short.max, ellipsis(toml), pango);
+                    body.short_text = Some(short_text);
+                }
+            }
+        }
+
+        /* a block displaying nothing may take up no space at all, rather than
+         * leaving a gap for its separator */
+        let collapsed =
+            toml.collapse_empty && body.full_text.as_deref().map_or(true, str::is_empty);
+        if collapsed {
+            body.separator = Some(false);
+            body.separator_block_width = Some(0);
+        }
+
+        /* separator_text is appended last, and it replaces the separator
+         * swaybar would draw */
+        let mut separator_len = 0;
+        if let (Some(ref separator_text), false) = (&toml.separator_text, collapsed) {
+            let separator = separator(
+                separator_text,
+                toml.separator_color.as_deref(),
+                body.markup == Some(Markup::Pango),
+            );
+            for text in [&mut body.full_text, &mut body.short_text]
+                .into_iter()
+                .flatten()
+            {
+                text.push_str(&separator);
+            }
+            separator_len = separator.len();
+            body.separator = Some(false);
+        }
+
+        /* swaybar may choke on unreasonably large bodies, even if they're
+         * otherwise valid */
+        let size = serialized_size(body);
+        if size > toml.max_body_size {
+            if toml.truncate_body {
+                if let Some(ref mut full_text) = body.full_text {
+                    /* the separator is never truncated, since that could
+                     * break its markup, so the text before it is cut instead.
+                     * every byte removed from full_text removes at least one
+                     * byte from the serialized body. */
+                    let text_len = full_text.len() - separator_len;
+                    let mut idx = text_len.saturating_sub(size - toml.max_body_size);
+                    while !full_text.is_char_boundary(idx) {
+                        idx -= 1;
+                    }
+                    *full_text = format!("{}{}", &full_text[..idx], &full_text[text_len..]).into();
+                }
+            }
+            let new_size = serialized_size(body);
+            tracing::warn!(
+                size,
+                new_size,
+                max_body_size = toml.max_body_size,
+                truncated = toml.truncate_body,
+                "body exceeds max_body_size"
+            );
+        }
 
         /* consider sending a refresh request */
-        let new_body_hash = Hash::new(body);
-        if old_body_hash == new_body_hash {
+        let new_body_hash = hash(body);
+        let changed = old_body_hash != new_body_hash;
+        if !changed {
             tracing::trace!("body unchanged, suppressing refresh request");
+        }
+        changed
+    }
+
+    /// Ask the bar to refresh the blocks.
+    ///
+    /// This gives up once the block is cancelled, since the bar may then be
+    /// waiting for the block to shut down rather than receiving messages. If
+    /// its channel were full, neither could make progress.
+    async fn request_refresh(&self) {
+        tracing::trace!("requesting bar refresh");
+        self.send_to_bar(BarMsg::RefreshBlocks).await;
+    }
+
+    /// Ask the bar to send the blocks, even if none changed. This gives up
+    /// once the block is cancelled, like [`Block::request_refresh`].
+    async fn request_pulse(&self) {
+        tracing::trace!("requesting bar pulse");
+        self.send_to_bar(BarMsg::Pulse).await;
+    }
+
+    async fn send_to_bar(&self, msg: BarMsg) {
+        tokio::select!(
+            () = self.cancel.cancelled() => {
+                tracing::trace!("cancelled, abandoning request");
+            }
+
+            result = self.bar_tx.send(msg) => {
+                result.expect("Bar must outlive its Blocks");
+            }
+        );
+    }
+
+    /// Apply a new configuration, updating the body from the latest command
+    /// output unless asked to execute the command again.
+    async fn reconfigure(&mut self, reconfigure: Reconfigure) {
+        self.command_dir = Self::command_dir(&reconfigure.toml, &reconfigure.global);
+        self.toml = reconfigure.toml;
+        self.global = reconfigure.global;
+        self.check_rlimits().await;
+        /* the trend starts over from the latest number */
+        self.history.trend = self.history.trend.map(|(_, latest)| (latest, latest));
+        if reconfigure.rerun {
+            self.refresh(false, None).await;
+        } else if let Some(ref immediate) = self.last_immediate {
+            let changed = Self::update_body(
+                immediate,
+                self.failed,
+                &self.global.body,
+                &self.toml,
+                &mut *self.body.write().await,
+                &mut self.history,
+                false,
+            );
+            if changed {
+                self.request_refresh().await;
+            }
+        }
+        self.update_hidden().await;
+    }
+
+    /// Update the body, by executing the command, or by starting it again
+    /// if it's `persistent`.
+    async fn refresh(&mut self, init: bool, click: Option<&ClickEvent>) {
+        if self.toml.persistent && self.toml.command.is_some() {
+            self.start_persistent(init, click).await;
+        } else {
+            self.regenerate_body(init, click).await;
+        }
+    }
+
+    /// Start the command of a `persistent` block, stopping it first if it's
+    /// already running. If this is because the block was clicked, the
+    /// command is told about `click` through its environment.
+    async fn start_persistent(&mut self, init: bool, click: Option<&ClickEvent>) {
+        let span = span!(
+            Level::INFO,
+            "block_start_persistent",
+            id = self.id,
+            init,
+            command = self.toml.command,
+        );
+
+        self.stop_persistent("restarted").await;
+        self.restart_at = None;
+        let Some(ref command) = self.toml.command else {
+            return;
+        };
+        let (program, args) = self.invocation(command);
+        let env = click.map(click_env).unwrap_or_default();
+        span.in_scope(|| self.trace_execution(&program, &args, &env));
+        let audited = args.last().map_or(&*program, Path::new).to_owned();
+        let spawned_at = SystemTime::now();
+        let start = Instant::now();
+        let spawned = span.in_scope(|| self.spawn(&program, &args, &env));
+
+        let _enter = span.enter();
+        match spawned {
+            Ok(mut child) => {
+                let stdout = child.stdout.take().map(Pipe::Stdout);
+                let stderr = child.stderr.take().map(Pipe::Stderr);
+                let (body, logged) = if self.toml.capture == Capture::Stderr {
+                    (stderr, stdout)
+                } else {
+                    (stdout, stderr)
+                };
+                if let Some(logged) = logged {
+                    task::spawn(log_lines(logged, span.clone()));
+                }
+                let Some(body) = body else {
+                    unreachable!("output the body is read from is piped");
+                };
+                self.persistent = Some(Persistent {
+                    child,
+                    reader: BufReader::new(body),
+                    line: Vec::new(),
+                    lossy: self.toml.lossy,
+                    group: String::new(),
+                    init,
+                    wrote_body: false,
+                    audited,
+                    spawned_at,
+                    start,
+                });
+            }
+
+            Err(err) => {
+                tracing::error!(err = format_args!("{err}"), "failed to execute command");
+                self.record_audit(
+                    &audited,
+                    spawned_at,
+                    start.elapsed(),
+                    None,
+                    Some(err.to_string()),
+                );
+                self.keep_output(init).await;
+                self.schedule_restart(false);
+            }
+        }
+    }
+
+    /// Update the body from `body`, written by the command of a
+    /// `persistent` block, or handle the command exiting if it's `None`.
+    async fn persistent_wrote(&mut self, body: Option<String>) {
+        let Some(body) = body else {
+            self.persistent_exited().await;
+            return;
+        };
+        tracing::trace!("command wrote body");
+        let init = self
+            .persistent
+            .as_mut()
+            .map_or(false, |persistent| mem::take(&mut persistent.init));
+        self.apply_output(body, false, false, init).await;
+    }
+
+    /// Handle the command of a `persistent` block closing its output, which
+    /// is usually because it exited. It's started again after a delay.
+    async fn persistent_exited(&mut self) {
+        let Some(mut persistent) = self.persistent.take() else {
+            return;
+        };
+        /* the command may have closed its output without exiting */
+        let grace = Duration::try_from_secs_f32(self.toml.kill_grace).unwrap_or_default();
+        let status = if let Ok(status) = time::timeout(grace, persistent.child.wait()).await {
+            status
         } else {
-            tracing::trace!("requesting bar refresh");
-            bar_tx
-                .send(BarMsg::RefreshBlocks)
-                .await
-                .expect("Bar must outlive its Blocks");
+            tracing::warn!("command closed its output, but kept running, so it's killed");
+            match persistent.child.kill().await {
+                Ok(()) => persistent.child.wait().await,
+                Err(err) => Err(err),
+            }
+        };
+        let (exit_status, error) = match status {
+            Ok(status) => {
+                tracing::warn!(
+                    exit_status = status.code(),
+                    "command exited, starting it again in {delay:?}",
+                    delay = self.restart_delay
+                );
+                (status.code(), None)
+            }
+            Err(err) => {
+                tracing::error!(err = format_args!("{err}"), "failed to wait for child");
+                (None, Some(err.to_string()))
+            }
+        };
+        self.record_audit(
+            &persistent.audited,
+            persistent.spawned_at,
+            persistent.start.elapsed(),
+            exit_status,
+            error,
+        );
+        self.keep_output(persistent.init).await;
+        self.schedule_restart(persistent.wrote_body);
+    }
+
+    /// Start the command of a `persistent` block again after a delay. The
+    /// delay doubles each time the command exits without writing a body,
+    /// up to a limit.
+    fn schedule_restart(&mut self, wrote_body: bool) {
+        if wrote_body {
+            self.restart_delay = Self::MIN_RESTART_DELAY;
+        }
+        self.restart_at = Some(time::Instant::now() + self.restart_delay);
+        self.restart_delay = self
+            .restart_delay
+            .saturating_mul(2)
+            .min(Self::MAX_RESTART_DELAY);
+    }
+
+    /// Kill the command of a `persistent` block, if it's running, along with
+    /// the rest of its process group, recording `reason` in the audit log.
+    async fn stop_persistent(&mut self, reason: &str) {
+        let Some(mut persistent) = self.persistent.take() else {
+            return;
+        };
+        tracing::trace!(reason, "stopping command");
+        /* the child has already been reaped if it has no pid, but others in
+         * its group may still be running */
+        if let Some(pid) = persistent.child.id() {
+            if let Err(err) = pidfile::send_signal_to_group(pid, libc::SIGKILL) {
+                tracing::error!(
+                    err = format_args!("{err:#}"),
+                    "failed to kill process group"
+                );
+            }
+        }
+        if let Err(err) = persistent.child.kill().await {
+            tracing::error!(err = format_args!("{err}"), "failed to kill command");
         }
+        self.record_audit(
+            &persistent.audited,
+            persistent.spawned_at,
+            persistent.start.elapsed(),
+            None,
+            Some(reason.to_owned()),
+        );
     }
 
-    async fn regenerate_body(&self, init: bool) {
+    /// Execute the command, if any, and update the body from its output. If
+    /// this is because the block was clicked, the command is told about
+    /// `click` through its environment.
+    async fn regenerate_body(&mut self, init: bool, click: Option<&ClickEvent>) {
         let span = span!(
             Level::INFO,
             "block_regen_body",
@@ -305,51 +1184,47 @@ impl Block {
         );
 
         let mut immediate = String::new();
-        if init && self.toml.command.is_some() {
-            /* we check that there's a command because otherwise we're updating
-             * the body twice with the same immediate value */
-            let _enter = span.enter();
-            // initialize with empty immediate
-            Self::update_body(
-                immediate.lines(),
-                &self.global_body,
-                &self.toml,
-                &mut *self.body.write().await,
-                self.bar_tx.clone(),
-            )
-            .await;
-        }
-
-        if let Some(ref program) = self.toml.command {
-            let mut command = Command::new(program);
-            command.kill_on_drop(true);
-            command.current_dir(&*self.command_dir);
-            command.stdout(Stdio::piped());
-            command.stderr(Stdio::null());
-            command.stdin(Stdio::null());
-            {
-                let _enter = span.enter();
-                tracing::trace!("executing command");
-            }
-            match command.spawn() {
+        let mut printed_nothing = false;
+        let mut timed_out = false;
+        let mut failed = false;
+        if let Some(ref command) = self.toml.command {
+            let (program, args) = self.invocation(command);
+            let env = click.map(click_env).unwrap_or_default();
+            span.in_scope(|| self.trace_execution(&program, &args, &env));
+            let spawned_at = SystemTime::now();
+            let start = Instant::now();
+            let mut exit_status = None;
+            let mut error = None;
+            match span.in_scope(|| self.spawn(&program, &args, &env)) {
                 Ok(child) => {
                     tokio::select!(
                         () = self.cancel.cancelled() => {
                             let _enter = span.enter();
                             tracing::trace!("command cancelled");
+                            error = Some("cancelled".to_owned());
                         }
 
-                        try_output = child.wait_with_output() => {
+                        try_output = wait_with_timeout(child, self.timeout()) => {
                             let _enter = span.enter();
                             match try_output {
-                                Ok(output) => {
+                                None => {
+                                    timed_out = true;
+                                    error = Some("timed out".to_owned());
+                                }
+
+                                Some(Ok(output)) => {
                                     span.record("exit_status", output.status.code());
+                                    exit_status = output.status.code();
                                     if !output.status.success() {
                                         tracing::warn!("command exited with failure");
                                     }
 
-                                    match String::from_utf8(output.stdout) {
-                                        Ok(stdout) => immediate = stdout,
+                                    let success = output.status.success();
+                                    match decode_output(self.captured(output), self.toml.lossy) {
+                                        Ok(stdout) => {
+                                            printed_nothing = success && stdout.trim().is_empty();
+                                            immediate = stdout;
+                                        }
 
                                         Err(err) => {
                                             tracing::error!(
@@ -360,9 +1235,10 @@ impl Block {
                                     }
                                 }
 
-                                Err(err) => {
+                                Some(Err(err)) => {
                                     let _enter = span.enter();
                                     tracing::error!(err = format_args!("{err}"), "failed to wait for child");
+                                    error = Some(err.to_string());
                                 }
                             }
                         }
@@ -372,26 +1248,403 @@ impl Block {
                 Err(err) => {
                     let _enter = span.enter();
                     tracing::error!(err = format_args!("{err}"), "failed to execute command");
+                    error = Some(err.to_string());
                 }
             }
+
+            /* a failed command's output is replaced by fallback_text, which
+             * is then displayed like any other output */
+            failed = exit_status != Some(0);
+            if failed {
+                if let Some(ref fallback_text) = self.toml.fallback_text {
+                    immediate = fallback_text.to_string();
+                    timed_out = false;
+                }
+            }
+
+            let audited = args.last().map_or(&*program, Path::new);
+            self.record_audit(audited, spawned_at, start.elapsed(), exit_status, error);
         }
 
         let _enter = span.enter();
-        Self::update_body(
-            immediate.lines(),
-            &self.global_body,
-            &self.toml,
-            &mut *self.body.write().await,
-            self.bar_tx.clone(),
-        )
-        .await;
+        if timed_out {
+            /* whatever the command printed before it was terminated is
+             * likely incomplete */
+            tracing::warn!(id = self.id, "command timed out, leaving body unchanged");
+            self.keep_output(init).await;
+        } else {
+            self.apply_output(immediate, failed, printed_nothing, init)
+                .await;
+        }
     }
 
-    fn interval_handle(&mut self) -> Option<JoinHandle<()>> {
+    /// Returns the names of the environment variables set for every command
+    /// of the block, globally and then locally.
+    fn env_names(&self) -> impl Iterator<Item = &str> {
+        self.global
+            .env
+            .iter()
+            .map(|(key, _)| &**key)
+            .chain(self.toml.env.keys().map(|key| &**key))
+    }
+
+    /// Log the command about to be executed. Like in the audit log, only the
+    /// names of environment variables are logged, since their values may be
+    /// secret.
+    fn trace_execution(&self, program: &Path, args: &[&str], env: &[(&str, String)]) {
+        let env: Vec<&str> = self
+            .env_names()
+            .chain(env.iter().map(|(key, _)| *key))
+            .collect();
+        tracing::trace!(
+            program = format_args!(r#""{}""#, program.display()),
+            args = format_args!("{args:?}"),
+            cwd = format_args!(r#""{}""#, self.command_dir.display()),
+            env = format_args!("{env:?}"),
+            "executing command"
+        );
+    }
+
+    /// Keep the body from the previous command output, but still tell the
+    /// bar if the block is now initialized.
+    async fn keep_output(&self, init: bool) {
+        if init {
+            self.initialized.store(true, Ordering::Relaxed);
+            self.request_refresh().await;
+        }
+    }
+
+    /// Execute `on_click`, if configured, with the details of `event` in its
+    /// environment. Then, the body is regenerated as if the block received
+    /// its signal, with the details of `event` in the environment of the
+    /// command as well.
+    async fn click(&mut self, event: ClickEvent) {
+        let span = span!(
+            Level::INFO,
+            "block_click",
+            id = self.id,
+            button = event.button,
+            on_click = self.toml.on_click,
+        );
+
+        if let Some(ref on_click) = self.toml.on_click {
+            let (program, args) = self.invocation(on_click);
+            let env = click_env(&event);
+            let spawned_at = SystemTime::now();
+            let start = Instant::now();
+            let mut exit_status = None;
+            let mut error = None;
+            match span.in_scope(|| self.spawn(&program, &args, &env)) {
+                Ok(child) => {
+                    tokio::select!(
+                        () = self.cancel.cancelled() => {
+                            let _enter = span.enter();
+                            tracing::trace!("on_click cancelled");
+                            return;
+                        }
+
+                        try_output = wait_with_timeout(child, self.timeout()) => {
+                            let _enter = span.enter();
+                            match try_output {
+                                None => error = Some("timed out".to_owned()),
+
+                                Some(Ok(output)) => {
+                                    exit_status = output.status.code();
+                                    if !output.status.success() {
+                                        tracing::warn!("on_click exited with failure");
+                                    }
+                                    if self.toml.capture == Capture::Stdout {
+                                        log_stderr(&output.stderr, output.status.success());
+                                    }
+                                }
+
+                                Some(Err(err)) => {
+                                    tracing::error!(err = format_args!("{err}"), "failed to wait for child");
+                                    error = Some(err.to_string());
+                                }
+                            }
+                        }
+                    );
+                }
+
+                Err(err) => {
+                    let _enter = span.enter();
+                    tracing::error!(err = format_args!("{err}"), "failed to execute on_click");
+                    error = Some(err.to_string());
+                }
+            }
+            let audited = args.last().map_or(&*program, Path::new);
+            self.record_audit(audited, spawned_at, start.elapsed(), exit_status, error);
+        }
+
+        self.refresh(false, Some(&event)).await;
+    }
+
+    /// Update the body from new command output `immediate`, and ask the bar
+    /// to refresh if needed. `failed` is whether the command failed.
+    async fn apply_output(
+        &mut self,
+        immediate: String,
+        failed: bool,
+        printed_nothing: bool,
+        init: bool,
+    ) {
+        let changed = Self::update_body(
+            &immediate,
+            failed,
+            &self.global.body,
+            &self.toml,
+            &mut *self.body.write().await,
+            &mut self.history,
+            true,
+        );
+        /* the bar may be waiting for every block to be initialized, so it's
+         * told even if the body is unchanged */
+        if init {
+            self.initialized.store(true, Ordering::Relaxed);
+        }
+        if self.toml.pulse {
+            self.request_pulse().await;
+        } else if changed || init {
+            self.request_refresh().await;
+        }
+        self.last_immediate = Some(immediate);
+        self.failed = failed;
+        self.printed_nothing = printed_nothing;
+        self.update_hidden().await;
+    }
+
+    /// Hide the block if its latest command printed nothing, unless
+    /// `keep_empty` or `icon` is set.
+    async fn update_hidden(&self) {
+        let hidden = self.printed_nothing && !self.toml.keep_empty && self.toml.icon.is_none();
+        /* the bar must be refreshed to show or hide the block, even if its
+         * body is unchanged */
+        if self.hidden.swap(hidden, Ordering::Relaxed) != hidden {
+            tracing::trace!(hidden, "visibility changed");
+            self.request_refresh().await;
+        }
+    }
+
+    fn command(&self, program: &Path, env: &[(&str, String)]) -> Command {
+        let mut command = Command::new(program);
+        command.kill_on_drop(true);
+        command.current_dir(&*self.command_dir);
+        /* click details are only set for commands executed because of a
+         * click, even if smolbar itself inherited them */
+        for key in CLICK_ENV {
+            command.env_remove(key);
+        }
+        command.envs(self.global.env.iter().map(|(key, value)| (key, value)));
+        command.envs(&self.toml.env);
+        command.envs(env.iter().map(|(key, value)| (key, value)));
+        /* standard error is always read: if it's not captured, it's logged.
+         * with both, standard error is redirected to standard output by a
+         * wrapper, but it's still read in case the wrapper can't be
+         * executed. */
+        let stdout = match self.toml.capture {
+            Capture::Stdout | Capture::Both => Stdio::piped(),
+            Capture::Stderr => Stdio::null(),
+        };
+        command.stdout(stdout);
+        command.stderr(Stdio::piped());
+        command.stdin(Stdio::null());
+        if self.toml.timeout.is_some() || self.toml.persistent {
+            /* a timed out or stopped persistent command is terminated along
+             * with any children it spawned */
+            command.process_group(0);
+        }
+        command
+    }
+
+    /// Returns the program to execute for `command`, and the arguments to pass
+    /// it. Through a shell, `command` is its last argument. Otherwise,
+    /// `command` is the program, resolved in `command_dir`.
+    fn invocation<'a>(&'a self, command: &'a str) -> (Cow<'a, Path>, Vec<&'a str>) {
+        let shell = self.toml.shell.as_ref().unwrap_or(&self.global.shell);
+        match shell.argv() {
+            /* the shell was validated to not be empty */
+            Some(mut args) if !args.is_empty() => {
+                let program = resolve_program(args.remove(0), &self.command_dir);
+                args.push(command);
+                (program, args)
+            }
+            _ => (resolve_program(command, &self.command_dir), Vec::new()),
+        }
+    }
+
+    /// Spawn `program`. Since setting resource limits or the umask directly
+    /// requires unsafe code, if configured, they're set by executing
+    /// `program` through `prlimit(1)` or `sh(1)`, respectively. Redirecting
+    /// standard error to standard output is done through `sh(1)` too.
+    ///
+    /// Each of these wrappers executes the next. If the outermost one can't
+    /// be executed, it's warned about and skipped, so the command still runs
+    /// with the rest, except for the umask: rather than creating files with
+    /// permissions it wasn't configured to, the command isn't run. Likewise,
+    /// `prlimit(1)` is skipped if it couldn't set the limits when they were
+    /// last checked (see [`Block::check_rlimits`]).
+    ///
+    /// `program` is passed `args`, and `env` is set in addition to the
+    /// global environment.
+    fn spawn(&self, program: &Path, args: &[&str], env: &[(&str, String)]) -> io::Result<Child> {
+        /* each wrapper is the purpose it's skipped without, whether it may
+         * be skipped, and the program and arguments which execute what
+         * follows them */
+        let mut wrappers: Vec<(&str, bool, Vec<String>)> = Vec::with_capacity(3);
+        let limits = self.rlimits();
+        if !limits.is_empty() && self.rlimits_settable {
+            let mut words = vec!["prlimit".to_owned()];
+            words.extend(limits);
+            words.push("--".to_owned());
+            wrappers.push(("resource limits", true, words));
+        }
+        if let Some(umask) = self.toml.umask.or(self.global.umask) {
+            let mut words: Vec<String> = ["sh", "-c", r#"umask "$1" && shift && exec "$@""#, "sh"]
+                .map(str::to_owned)
+                .into();
+            words.push(umask.into());
+            wrappers.push(("umask", false, words));
+        }
+        if self.toml.capture == Capture::Both {
+            wrappers.push((
+                "redirection of standard error",
+                true,
+                ["sh", "-c", r#"exec "$@" 2>&1"#, "sh"]
+                    .map(str::to_owned)
+                    .into(),
+            ));
+        }
+
+        for outermost in 0..wrappers.len() {
+            let mut words = wrappers[outermost..].iter().flat_map(|(_, _, words)| words);
+            let wrapper = words.next().expect("wrappers are never empty");
+            let mut command = self.command(Path::new(wrapper), env);
+            command.args(words).arg(program).args(args);
+            let (purpose, skippable, _) = wrappers[outermost];
+            match command.spawn() {
+                Ok(child) => return Ok(child),
+                Err(err) if skippable => tracing::warn!(
+                    "failed to execute {wrapper}, running command without {purpose}: {err}"
+                ),
+                Err(err) => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("failed to execute {wrapper} for {purpose}: {err}"),
+                    ))
+                }
+            }
+        }
+
+        self.command(program, env).args(args).spawn()
+    }
+
+    /// Returns the arguments to `prlimit(1)` which set the configured resource
+    /// limits, if any.
+    fn rlimits(&self) -> Vec<String> {
+        [
+            self.toml.rlimit_cpu.map(|cpu| format!("--cpu={cpu}")),
+            self.toml.rlimit_mem.map(|mem| format!("--as={mem}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Check whether `prlimit(1)` can set the configured resource limits, by
+    /// having it set them on itself. Commands inherit their limits from
+    /// smolbar, as `prlimit(1)` does, so if it fails, such as because a
+    /// limit is above smolbar's own hard limit, so would every command's.
+    /// They're run without the limits instead, with a warning now.
+    async fn check_rlimits(&mut self) {
+        let limits = self.rlimits();
+        if limits.is_empty() {
+            self.rlimits_settable = true;
+            return;
+        }
+        let mut command = self.command(Path::new("prlimit"), &[]);
+        command.args(limits).stdout(Stdio::null());
+        self.rlimits_settable = match command.output().await {
+            Ok(output) if output.status.success() => true,
+            Ok(output) => {
+                tracing::warn!(
+                    id = self.id,
+                    status = format_args!("{}", output.status),
+                    stderr = String::from_utf8_lossy(&output.stderr).trim(),
+                    "prlimit failed to set resource limits, running command without them"
+                );
+                false
+            }
+            Err(err) => {
+                tracing::warn!(
+                    id = self.id,
+                    "failed to execute prlimit, running command without resource limits: {err}"
+                );
+                false
+            }
+        };
+    }
+
+    /// Returns the output of a finished command which its body is read from,
+    /// according to `capture`.
+    /// If the body isn't read from standard error, it's logged instead.
+    fn captured(&self, output: Output) -> Vec<u8> {
+        match self.toml.capture {
+            Capture::Stdout => {
+                log_stderr(&output.stderr, output.status.success());
+                output.stdout
+            }
+            Capture::Stderr => output.stderr,
+            Capture::Both => {
+                let mut captured = output.stdout;
+                captured.extend(output.stderr);
+                captured
+            }
+        }
+    }
+
+    /// Returns the configured `timeout` and `kill_grace`, if there's a
+    /// timeout.
+    fn timeout(&self) -> Option<(Duration, Duration)> {
+        /* these were validated when reading the config */
+        let timeout = Duration::try_from_secs_f32(self.toml.timeout?).ok()?;
+        let grace = Duration::try_from_secs_f32(self.toml.kill_grace).ok()?;
+        Some((timeout, grace))
+    }
+
+    /// Record a command invocation in the audit log, if enabled.
+    fn record_audit(
+        &self,
+        program: &Path,
+        spawned_at: SystemTime,
+        duration: Duration,
+        exit_status: Option<i32>,
+        error: Option<String>,
+    ) {
+        if let Some(ref audit_log) = self.global.audit_log {
+            audit_log.record(&audit::Entry {
+                time: spawned_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                block: self.id,
+                program,
+                cwd: &self.command_dir,
+                env: self.env_names().collect(),
+                exit_status,
+                duration: duration.as_secs_f64(),
+                error,
+            });
+        }
+    }
+
+    fn interval_handle(&mut self) -> Option<JoinHandle<()>> {
         (!self.interval_handle_created).then(|| {
             self.interval_handle_created = true;
             let tx = self.tx.clone();
             let toml_interval = self.toml.interval;
+            let precise_interval = self.toml.precise_interval;
+            let run_on_start = self.toml.run_on_start;
             let id = self.id;
             task::spawn(async move {
                 let span = span!(
@@ -408,7 +1661,8 @@ impl Block {
                                 let _enter = span.enter();
                                 tracing::error!("can't have timeout of zero");
                             } else {
-                                if dur < Duration::from_millis(1) {
+                                let sub_millisecond = dur < Duration::from_millis(1);
+                                if sub_millisecond && !precise_interval {
                                     let _enter = span.enter();
                                     dur = Duration::from_millis(1);
                                     span.record("interval", format_args!("{dur:?}"));
@@ -419,8 +1673,25 @@ impl Block {
                                     span.record("interval", format_args!("{dur:?}"));
                                 }
 
-                                let mut interval = time::interval(dur);
-                                interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+                                /* the first tick of an interval completes
+                                 * immediately, which would defeat
+                                 * run_on_start */
+                                let mut interval = if run_on_start {
+                                    time::interval(dur)
+                                } else {
+                                    time::interval_at(time::Instant::now() + dur, dur)
+                                };
+                                /* the timer itself is no finer than a
+                                 * millisecond, so sub-millisecond ticks are
+                                 * only honored by catching up on those which
+                                 * were missed */
+                                interval.set_missed_tick_behavior(
+                                    if sub_millisecond && precise_interval {
+                                        time::MissedTickBehavior::Burst
+                                    } else {
+                                        time::MissedTickBehavior::Skip
+                                    },
+                                );
 
                                 loop {
                                     interval.tick().await;
@@ -447,7 +1718,7 @@ impl Block {
     fn signal_handle(&mut self) -> Option<JoinHandle<()>> {
         (!self.signal_handle_created).then(|| {
             self.signal_handle_created = true;
-            let tx = self.tx.clone();
+            let signaller = self.signaller();
             let toml_signal = self.toml.signal;
             let id = self.id;
             task::spawn(async move {
@@ -459,12 +1730,11 @@ impl Block {
                 if let Some(signum) = toml_signal {
                     let sig_kind = SignalKind::from_raw(signum.as_raw());
                     if let Ok(mut sig) = signal(sig_kind) {
-                        while let Some(()) = sig.recv().await {
+                        loop {
+                            crate::recv_signal(&mut sig, sig_kind).await;
                             let _enter = span.enter();
                             tracing::trace!("received signal, requesting Block regenerate body");
-                            tx.send(RegenBody { init: false })
-                                .await
-                                .expect("Block must outlive signal handle");
+                            signaller.signal().await;
                         }
                     } else {
                         let _enter = span.enter();
@@ -477,4 +1747,775 @@ impl Block {
             })
         })
     }
+
+    /// Returns the path of the file whose changes rerun the command, if
+    /// any.
+    fn watched_path(&self) -> Option<PathBuf> {
+        match self.toml.watch.as_ref()? {
+            Watch::Command(false) => None,
+            Watch::Command(true) => {
+                /* this was validated when reading the config */
+                let command = self.toml.command.as_deref()?;
+                let shell = self.toml.shell.as_ref().unwrap_or(&self.global.shell);
+                if let Some(program) = shell
+                    .program(command)
+                    .filter(|program| program.contains('/'))
+                {
+                    Some(resolve_program(program, &self.command_dir).into_owned())
+                } else {
+                    tracing::warn!(
+                        "command's program is searched for in $PATH or unknown, so it can't be watched (set watch to its path instead)"
+                    );
+                    None
+                }
+            }
+            Watch::Path(path) => Some(self.command_dir.join(path)),
+        }
+    }
+
+    fn watch_handle(&mut self) -> Option<JoinHandle<()>> {
+        (!self.watch_handle_created).then(|| {
+            self.watch_handle_created = true;
+            let tx = self.tx.clone();
+            let id = self.id;
+            let span = span!(Level::INFO, "block_watch", id, path = field::Empty);
+            let path = span.in_scope(|| self.watched_path());
+            task::spawn(async move {
+                let Some(path) = path else {
+                    let _enter = span.enter();
+                    tracing::trace!("no file watched");
+                    return;
+                };
+                span.record("path", format_args!(r#""{}""#, path.display()));
+
+                let mut watcher = FileWatcher::new(path);
+                if !watcher.exists() {
+                    let _enter = span.enter();
+                    tracing::warn!("watched file doesn't exist yet");
+                }
+                loop {
+                    watcher.changed().await;
+                    let _enter = span.enter();
+                    tracing::trace!("watched file changed, requesting Block regenerate body");
+                    tx.send(RegenBody { init: false })
+                        .await
+                        .expect("Block must outlive watch handle");
+                }
+            })
+        })
+    }
+}
+
+/// Wait for `child` to exit and collect its output. If it takes longer than
+/// the timeout, its process group is sent SIGTERM, and then SIGKILL if it's
+/// still running after the grace period. Returns `None` if it timed out.
+async fn wait_with_timeout(
+    child: Child,
+    timeout: Option<(Duration, Duration)>,
+) -> Option<io::Result<Output>> {
+    let pid = child.id();
+    let output = child.wait_with_output();
+    tokio::pin!(output);
+
+    let Some((timeout, grace)) = timeout else {
+        return Some(output.await);
+    };
+    if let Ok(result) = time::timeout(timeout, &mut output).await {
+        return Some(result);
+    }
+
+    /* the child has already been reaped if it has no pid */
+    let pid = pid?;
+    for (signum, signame) in [(libc::SIGTERM, "SIGTERM"), (libc::SIGKILL, "SIGKILL")] {
+        tracing::warn!(
+            pid,
+            "command timed out, sending {signame} to its process group"
+        );
+        if let Err(err) = pidfile::send_signal_to_group(pid, signum) {
+            tracing::error!(err = format_args!("{err:#}"), "failed to send {signame}");
+        }
+        if time::timeout(grace, &mut output).await.is_ok() {
+            return None;
+        }
+    }
+    tracing::error!(
+        pid,
+        "command is still running after SIGKILL, giving up on it"
+    );
+    None
+}
+
+/// Names of the environment variables set by [`click_env`].
+const CLICK_ENV: [&str; 10] = [
+    "SMOLBAR_BUTTON",
+    "SMOLBAR_EVENT",
+    "SMOLBAR_X",
+    "SMOLBAR_Y",
+    "SMOLBAR_RELATIVE_X",
+    "SMOLBAR_RELATIVE_Y",
+    "SMOLBAR_WIDTH",
+    "SMOLBAR_HEIGHT",
+    "SMOLBAR_NAME",
+    "SMOLBAR_INSTANCE",
+];
+
+/// Log what a command wrote to standard error, if anything, as an error if
+/// it exited with failure, or else as a warning. Past
+/// [`STDERR_LOG_LIMIT`] bytes, it's truncated.
+fn log_stderr(stderr: &[u8], success: bool) {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim_end();
+    if stderr.is_empty() {
+        return;
+    }
+    let mut end = stderr.len().min(STDERR_LOG_LIMIT);
+    while !stderr.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = match stderr.len() - end {
+        0 => String::new(),
+        rest => format!(" ({rest} more bytes truncated)"),
+    };
+    let stderr = &stderr[..end];
+    if success {
+        tracing::warn!("command wrote to stderr: {stderr}{truncated}");
+    } else {
+        tracing::error!("command wrote to stderr: {stderr}{truncated}");
+    }
+}
+
+/// Log each line of `output`, which a `persistent` command writes to
+/// standard error, until it's closed.
+async fn log_lines(output: Pipe, span: tracing::Span) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let _enter = span.enter();
+        tracing::warn!("command wrote to stderr: {line}");
+    }
+}
+
+/// Maximum number of bytes of standard error logged for each command.
+const STDERR_LOG_LIMIT: usize = 2048;
+
+/// Returns the environment variables describing `event`, with which
+/// `on_click` and the command it triggers are executed. `SMOLBAR_NAME` and
+/// `SMOLBAR_INSTANCE` are only set if the event has them.
+fn click_env(event: &ClickEvent) -> Vec<(&'static str, String)> {
+    let [button, event_code, x, y, relative_x, relative_y, width, height, name, instance] =
+        CLICK_ENV;
+    let mut env = vec![
+        (button, event.button.to_string()),
+        (event_code, event.event.to_string()),
+        (x, event.x.to_string()),
+        (y, event.y.to_string()),
+        (relative_x, event.relative_x.to_string()),
+        (relative_y, event.relative_y.to_string()),
+        (width, event.width.to_string()),
+        (height, event.height.to_string()),
+    ];
+    if let Some(ref value) = event.name {
+        env.push((name, value.to_string()));
+    }
+    if let Some(ref value) = event.instance {
+        env.push((instance, value.to_string()));
+    }
+    env
+}
+
+/// Decode `bytes` of a command's output as UTF-8. If `lossy`, invalid UTF-8
+/// is warned about and replaced with U+FFFD, rather than being an error.
+fn decode_output(bytes: Vec<u8>, lossy: bool) -> Result<String, FromUtf8Error> {
+    match String::from_utf8(bytes) {
+        Err(err) if lossy => {
+            tracing::warn!(
+                err = format_args!("{err}"),
+                "command produced invalid utf8, replacing it"
+            );
+            Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+        }
+        result => result,
+    }
+}
+
+/// Returns the line `immediate` of a command's output for the color `field`,
+/// unless it isn't a valid color, in which case it's warned about and the
+/// configured color is used instead. An empty line is passed along as is.
+fn color_immediate<'a>(field: &str, immediate: Option<&'a str>) -> Option<&'a str> {
+    immediate.filter(|color| {
+        let valid = color.is_empty() || protocol::is_valid_color(color);
+        if !valid {
+            tracing::warn!(
+                "command printed invalid {field} \"{color}\" (expected #RRGGBB or #RRGGBBAA), ignoring it"
+            );
+        }
+        valid
+    })
+}
+
+/// Returns `text` to append as a separator. If `pango` is true, it's escaped,
+/// and colored by `color` if given. Otherwise, `color` is ignored.
+fn separator<'a>(text: &'a str, color: Option<&str>, pango: bool) -> Cow<'a, str> {
+    if !pango {
+        return Cow::Borrowed(text);
+    }
+    let escaped = protocol::escape_markup(text);
+    Cow::Owned(match color {
+        Some(color) => format!(r#"<span foreground="{color}">{escaped}</span>"#),
+        None => escaped,
+    })
+}
+
+/// Returns the path of `program` to execute in `command_dir`.
+///
+/// Paths starting with "./" or "../" are relative to `command_dir`. How std
+/// resolves them when the working directory is changed is unspecified, so they
+/// are joined explicitly. Other programs are left as is, to be searched for in
+/// `$PATH` as usual.
+pub fn resolve_program<'a>(program: &'a str, command_dir: &Path) -> Cow<'a, Path> {
+    let program = Path::new(program);
+    if program.starts_with(".") || program.starts_with("..") {
+        Cow::Owned(command_dir.join(program))
+    } else {
+        Cow::Borrowed(program)
+    }
+}
+
+/// Returns `text` without any trailing blank lines, which contain nothing but
+/// whitespace. Leading blank lines are kept, since they're empty properties
+/// before those which follow, like an empty `full_text` before a `color`.
+fn trim_blank_lines(text: &str) -> &str {
+    let end = text.len()
+        - text
+            .split_inclusive('\n')
+            .rev()
+            .take_while(|line| line.trim().is_empty())
+            .map(str::len)
+            .sum::<usize>();
+    &text[..end]
+}
+
+/// Parse the number at the start of `text`, ignoring leading whitespace and
+/// anything following the number. For example, "15% left" is 15.
+fn leading_number(text: &str) -> Option<f64> {
+    text[leading_number_span(text)?].parse().ok()
+}
+
+/// Returns the range of bytes of the number at the start of `text`, like
+/// [`leading_number`].
+fn leading_number_span(text: &str) -> Option<Range<usize>> {
+    let start = text.len() - text.trim_start().len();
+    let mut end = start;
+    let mut seen_digit = false;
+    let mut seen_point = false;
+    for (idx, ch) in text[start..].char_indices() {
+        match ch {
+            '+' | '-' if idx == 0 => {}
+            '0'..='9' => seen_digit = true,
+            '.' if !seen_point => seen_point = true,
+            _ => break,
+        }
+        end = start + idx + ch.len_utf8();
+    }
+    if seen_digit {
+        /* a trailing point isn't part of the number */
+        let len = text[start..end].trim_end_matches('.').len();
+        Some(start..start + len)
+    } else {
+        None
+    }
+}
+
+/// Replace the number at the start of `text`, like [`leading_number`], with
+/// `f` of it. If there's no number, `text` is left as is.
+fn map_leading_number(text: &mut CowStr, f: impl FnOnce(&str) -> String) {
+    if let Some(span) = leading_number_span(text) {
+        let replaced = f(&text[span.clone()]);
+        *text = format!("{}{replaced}{}", &text[..span.start], &text[span.end..]).into();
+    }
+}
+
+/// Returns the size, in bytes, of `body` serialized as compact JSON.
+fn serialized_size(body: &Body) -> usize {
+    struct Counter(usize);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    ser::to_writer(&mut counter, body).expect("Body serialization is infallible");
+    counter.0
+}
+
+/// Returns the configured ellipsis of `toml`, or the default.
+fn ellipsis(toml: &TomlBlock) -> &str {
+    toml.ellipsis
+        .as_deref()
+        .unwrap_or(TomlBlock::DEFAULT_ELLIPSIS)
+}
+
+/// Truncate `text` to at most `max_length` characters, replacing the truncated
+/// characters with `ellipsis`. The ellipsis counts towards `max_length`.
+///
+/// `text` is only ever split on a [`char`] boundary. If `pango` is set, `text`
+/// is Pango markup: tags count for nothing and are all kept, even those after
+/// the cut, so the markup stays balanced, and an entity such as `&amp;` counts
+/// as the one character it displays and is never split.
+fn truncate(text: &mut CowStr, max_length: usize, ellipsis: &str, pango: bool) {
+    /* split text into the units it displays, each paired with whether it
+     * takes up a character */
+    let mut units = Vec::new();
+    let mut rest = text.as_str();
+    while let Some(first) = rest.chars().next() {
+        let len = match first {
+            '<' if pango => rest.find('>').map_or(rest.len(), |idx| idx + 1),
+            '&' if pango => rest
+                .find(';')
+                .filter(|&idx| {
+                    rest[1..idx]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '#')
+                })
+                .map_or(1, |idx| idx + 1),
+            _ => first.len_utf8(),
+        };
+        units.push((&rest[..len], !(pango && first == '<')));
+        rest = &rest[len..];
+    }
+
+    if units.iter().filter(|(_, displayed)| *displayed).count() <= max_length {
+        return;
+    }
+
+    let mut keep = max_length.saturating_sub(ellipsis.chars().count());
+    let mut truncated = String::with_capacity(text.len() + ellipsis.len());
+    let mut ellipsized = false;
+    for (unit, displayed) in units {
+        if !displayed {
+            truncated.push_str(unit);
+        } else if keep > 0 {
+            truncated.push_str(unit);
+            keep -= 1;
+        } else if !ellipsized {
+            truncated.push_str(ellipsis);
+            ellipsized = true;
+        }
+    }
+    *text = truncated.into();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::{env, fs, process};
+
+    /// Deadline for a block's command to first finish
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn global() -> Arc<Global> {
+        Arc::new(Global {
+            body: Body::new(),
+            command_dir: Arc::new(env::temp_dir()),
+            env: Vec::new(),
+            audit_log: None,
+            umask: None,
+            shell: Shell::default(),
+        })
+    }
+
+    /// Run a block configured as `toml` until its command first finishes,
+    /// then shut it down. Returns its body and whether it's hidden.
+    async fn run(toml: &str) -> (Body, bool) {
+        let toml: TomlBlock = toml::from_str(toml).unwrap();
+        let (bar_tx, _bar_rx) = mpsc::channel(16);
+        let (block, cancel) = Block::new(toml, global(), bar_tx, 0, 1);
+        let body = block.body();
+        let hidden = block.hidden();
+        let initialized = block.initialized();
+        let handle = task::spawn(block.listen());
+
+        time::timeout(TIMEOUT, async {
+            while !initialized.load(Ordering::Relaxed) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("block command must finish");
+        /* the output is fully applied before the block handles cancellation */
+        cancel.cancel();
+        handle.await.unwrap();
+
+        let body = body.read().await.clone();
+        (body, hidden.load(Ordering::Relaxed))
+    }
+
+    /// Simulating the block's signal executes its command again, like
+    /// receiving it would.
+    #[tokio::test]
+    async fn signal_executes_command_again() {
+        let path = env::temp_dir().join(format!("smolbar-test-signal-{}", process::id()));
+        let toml: TomlBlock = toml::from_str(&format!(
+            r#"command = "printf x >> '{path}' && wc -c < '{path}'""#,
+            path = path.display()
+        ))
+        .unwrap();
+        let (bar_tx, _bar_rx) = mpsc::channel(16);
+        let (block, cancel) = Block::new(toml, global(), bar_tx, 0, 1);
+        let body = block.body();
+        let signaller = block.signaller();
+        let handle = task::spawn(block.listen());
+
+        /* the command runs once on start, and once more when signalled */
+        let wait_for = |expected: &'static str| {
+            let body = Arc::clone(&body);
+            time::timeout(TIMEOUT, async move {
+                while body.read().await.full_text.as_deref() != Some(expected) {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        wait_for("1")
+            .await
+            .expect("block command must run on start");
+        signaller.signal().await;
+        wait_for("2").await.expect("block command must run again");
+
+        cancel.cancel();
+        handle.await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Stopping a persistent command kills whatever else it started too.
+    #[tokio::test]
+    async fn stop_persistent_kills_process_group() {
+        /* a killed process may linger as a zombie until it's reaped */
+        fn is_running(pid: &str) -> bool {
+            fs::read_to_string(format!("/proc/{pid}/stat")).map_or(false, |stat| {
+                stat.rsplit_once(") ")
+                    .map_or(false, |(_, rest)| !rest.starts_with('Z'))
+            })
+        }
+
+        let path = env::temp_dir().join(format!("smolbar-test-persistent-{}", process::id()));
+        let toml: TomlBlock = toml::from_str(&format!(
+            r#"
+            command = "sleep 30 & echo $! > '{path}'; echo started; echo; wait"
+            persistent = true
+            "#,
+            path = path.display()
+        ))
+        .unwrap();
+        let (bar_tx, _bar_rx) = mpsc::channel(16);
+        let (block, cancel) = Block::new(toml, global(), bar_tx, 0, 1);
+        let body = block.body();
+        let handle = task::spawn(block.listen());
+
+        time::timeout(TIMEOUT, async {
+            while body.read().await.full_text.as_deref() != Some("started") {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("persistent command must write a body");
+        let pid = fs::read_to_string(&path).unwrap();
+        let pid = pid.trim();
+        assert!(is_running(pid));
+
+        cancel.cancel();
+        handle.await.unwrap();
+        time::timeout(TIMEOUT, async {
+            while is_running(pid) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("background process must be killed");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_output_hides_block() {
+        let (body, hidden) = run(r#"command = "true""#).await;
+        assert!(hidden);
+        assert_eq!(body.full_text, None);
+    }
+
+    #[tokio::test]
+    async fn empty_output_hides_block_with_affixes() {
+        let (_, hidden) = run(r#"
+            command = "true"
+            prefix = "<"
+            postfix = ">"
+            "#)
+        .await;
+        assert!(hidden);
+    }
+
+    #[tokio::test]
+    async fn empty_output_with_keep_empty_shows_block() {
+        let (_, hidden) = run(r#"
+            command = "true"
+            prefix = "<"
+            postfix = ">"
+            keep_empty = true
+            "#)
+        .await;
+        assert!(!hidden);
+    }
+
+    #[tokio::test]
+    async fn output_shows_block_with_affixes() {
+        let (body, hidden) = run(r#"
+            command = "echo hi"
+            prefix = "<"
+            postfix = ">"
+            "#)
+        .await;
+        assert!(!hidden);
+        assert_eq!(body.full_text.as_deref(), Some("<hi>"));
+    }
+
+    /// Shell syntax in the command is expanded by the shell when it's
+    /// executed, rather than when the configuration is loaded.
+    #[tokio::test]
+    async fn shell_expands_command() {
+        let (body, _) = run(r#"command = "set -- one; echo $1 x${SMOLBAR_TEST_UNSET}x $$""#).await;
+        let full_text = body.full_text.unwrap();
+        let (start, pid) = full_text.rsplit_once(' ').unwrap();
+        assert_eq!(start, "one xx");
+        assert!(pid.parse::<u32>().is_ok(), "{pid:?}");
+    }
+
+    #[test]
+    fn trims_only_trailing_blank_lines() {
+        assert_eq!(trim_blank_lines("a\n \n\t\n\n"), "a\n");
+        assert_eq!(trim_blank_lines("\n\na\nb"), "\n\na\nb");
+        assert_eq!(trim_blank_lines(" \n\n"), "");
+    }
+
+    #[tokio::test]
+    async fn blank_full_text_with_color() {
+        let (body, hidden) = run(r#"command = "printf '\\n\\n#ff0000\\n\\n'""#).await;
+        assert!(!hidden);
+        assert_eq!(body.full_text.as_deref().unwrap_or_default(), "");
+        assert_eq!(body.short_text.as_deref().unwrap_or_default(), "");
+        assert_eq!(body.color.as_deref(), Some("#ff0000"));
+        assert_eq!(body.background, None);
+    }
+
+    /// Returns the body of a block configured as `toml` whose command printed
+    /// `immediate`.
+    fn body(toml: &str, immediate: &str) -> Body {
+        let toml: TomlBlock = toml::from_str(toml).unwrap();
+        Block::body_from_output(immediate, &Body::new(), &toml)
+    }
+
+    #[test]
+    fn escape_pango_escapes_output() {
+        let body = body(
+            r#"
+            markup = "pango"
+            escape_pango = true
+            prefix = "<i>"
+            postfix = "</i>"
+            "#,
+            "a & <b>",
+        );
+        assert_eq!(body.full_text.as_deref(), Some("<i>a &amp; &lt;b&gt;</i>"));
+    }
+
+    /// Markup in `format` is configured, so only the values filling it in are
+    /// escaped.
+    #[test]
+    fn escape_pango_leaves_format() {
+        let body = body(
+            r#"
+            markup = "pango"
+            escape_pango = true
+            format = "<b>{title}</b>"
+            "#,
+            "title=a & b",
+        );
+        assert_eq!(body.full_text.as_deref(), Some("<b>a &amp; b</b>"));
+    }
+
+    #[test]
+    fn escape_pango_needs_pango_markup() {
+        let body = body(
+            r#"
+            escape_pango = true
+            format = "<b>{title}</b>"
+            "#,
+            "title=a & b",
+        );
+        assert_eq!(body.full_text.as_deref(), Some("<b>a & b</b>"));
+    }
+
+    /// With Pango markup, tags don't count towards `max_length`, and are kept
+    /// so the markup stays balanced.
+    #[test]
+    fn max_length_keeps_pango_tags() {
+        let toml = r#"
+            markup = "pango"
+            prefix = "<b>"
+            postfix = "</b>"
+            max_length = 6
+            "#;
+        assert_eq!(body(toml, "ab").full_text.as_deref(), Some("<b>ab</b>"));
+        assert_eq!(
+            body(toml, "abcdef").full_text.as_deref(),
+            Some("<b>abcdef</b>")
+        );
+        assert_eq!(
+            body(toml, "abcdefgh").full_text.as_deref(),
+            Some("<b>abcde…</b>")
+        );
+    }
+
+    /// An escaped character counts once, and its entity is never split.
+    #[test]
+    fn max_length_keeps_pango_entities() {
+        let body = body(
+            r#"
+            markup = "pango"
+            escape_pango = true
+            max_length = 5
+            "#,
+            "a & bcdef",
+        );
+        assert_eq!(body.full_text.as_deref(), Some("a &amp; …"));
+    }
+
+    /// A truncated body keeps its separator whole, so its markup stays
+    /// intact.
+    #[test]
+    fn truncate_body_keeps_separator() {
+        let toml = r##"
+            markup = "pango"
+            separator_text = "|"
+            separator_color = "#ff0000"
+            max_body_size = 120
+            truncate_body = true
+            "##;
+        let body = body(toml, &"a".repeat(100));
+        let full_text = body.full_text.as_deref().unwrap();
+        assert!(full_text.starts_with('a'));
+        assert!(full_text.ends_with(r##"<span foreground="#ff0000">|</span>"##));
+        assert!(serialized_size(&body) <= 120);
+    }
+
+    /// Without Pango markup, what looks like a tag is displayed as is.
+    #[test]
+    fn max_length_counts_plain_tags() {
+        let body = body(
+            r#"
+            prefix = "<b>"
+            max_length = 6
+            "#,
+            "abcdef",
+        );
+        assert_eq!(body.full_text.as_deref(), Some("<b>ab…"));
+    }
+
+    #[tokio::test]
+    async fn escape_pango_leaves_fallback_text() {
+        let (body, _) = run(r#"
+            command = "echo 'a & b'; false"
+            markup = "pango"
+            escape_pango = true
+            fallback_text = "<b>failed</b>"
+            "#)
+        .await;
+        assert_eq!(body.full_text.as_deref(), Some("<b>failed</b>"));
+    }
+
+    /// If `prlimit` can't be executed, the command still runs with its umask
+    /// and standard error redirected, which are set by later wrappers.
+    #[tokio::test]
+    async fn missing_wrapper_keeps_the_rest() {
+        /* $PATH has a shell, but no prlimit */
+        let dir = env::temp_dir().join(format!("smolbar-test-wrapper-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let sh = dir.join("sh");
+        if !sh.exists() {
+            symlink("/bin/sh", &sh).unwrap();
+        }
+
+        let (body, _) = run(&format!(
+            r#"
+            command = "umask; echo err >&2"
+            rlimit_cpu = 10
+            umask = "077"
+            capture = "both"
+            env = {{ PATH = "{dir}" }}
+            "#,
+            dir = dir.display()
+        ))
+        .await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body.full_text.as_deref(), Some("0077"));
+        assert_eq!(body.short_text.as_deref(), Some("err"));
+    }
+
+    /// If the shell setting the umask can't be executed, the command isn't
+    /// run without it.
+    #[tokio::test]
+    async fn missing_umask_wrapper_fails() {
+        /* $PATH has no shell, but the command's shell is absolute */
+        let dir = env::temp_dir().join(format!("smolbar-test-umask-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (body, _) = run(&format!(
+            r#"
+            command = "echo ran"
+            shell = ["/bin/sh", "-c"]
+            umask = "077"
+            fallback_text = "failed"
+            env = {{ PATH = "{dir}" }}
+            "#,
+            dir = dir.display()
+        ))
+        .await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body.full_text.as_deref(), Some("failed"));
+    }
+
+    /// If `prlimit` can't set the limits, the command runs without them.
+    #[tokio::test]
+    async fn failing_prlimit_is_skipped() {
+        let dir = env::temp_dir().join(format!("smolbar-test-prlimit-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prlimit = dir.join("prlimit");
+        fs::write(
+            &prlimit,
+            "#!/bin/sh\necho 'failed to set limits' >&2\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&prlimit, fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("/bin/sh", dir.join("sh")).unwrap();
+
+        let (body, _) = run(&format!(
+            r#"
+            command = "echo ran"
+            rlimit_cpu = 10
+            env = {{ PATH = "{dir}" }}
+            "#,
+            dir = dir.display()
+        ))
+        .await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(body.full_text.as_deref(), Some("ran"));
+    }
 }
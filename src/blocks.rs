@@ -1,36 +1,132 @@
 // copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
 // licensed under GPL-3.0-or-later
 
+use cowstr::CowStr;
 use tokio::sync::{mpsc, RwLock};
 use tokio::task::{self, JoinHandle};
+use tokio::time;
 use tokio_util::sync::CancellationToken;
 
 use alloc::sync::Arc;
-use std::path::PathBuf;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 use crate::bar::BarMsg;
-use crate::block::Block;
+use crate::block::{Block, Global, Reconfigure};
 use crate::config::TomlBlock;
-use crate::protocol::Body;
+use crate::protocol::{Body, ClickEvent};
+
+/// Handle to a running [`Block`], owned by [`Blocks`].
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub struct BlockHandle {
+    handle: JoinHandle<()>,
+    cancel: CancellationToken,
+    /// The block's current body
+    pub body: Arc<RwLock<Body>>,
+    /// The block's configured name, from its local scope
+    pub name: Option<CowStr>,
+    /// The block's configured priority
+    pub priority: i32,
+    /// The block's configured group
+    pub group: Option<CowStr>,
+    /// Whether the block is hidden
+    pub hidden: Arc<AtomicBool>,
+    /// Whether the block is initialized
+    pub initialized: Arc<AtomicBool>,
+    /// The block's configuration, and a sender through which it's
+    /// reconfigured while running
+    config: (TomlBlock, mpsc::Sender<Reconfigure>),
+    /// Sender through which click events are passed to the block
+    clicker: mpsc::Sender<ClickEvent>,
+}
+
+impl BlockHandle {
+    /// Returns whether the block has no command, so its content never
+    /// changes unless it's reconfigured.
+    pub fn is_static(&self) -> bool {
+        self.config.0.command.is_none()
+    }
+
+    /// Pass a click event to the block, without waiting for it to be
+    /// handled. If the block is still busy with earlier click events, it's
+    /// dropped.
+    pub fn click(&self, event: ClickEvent) {
+        /* if the channel is closed, the block is shutting down */
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.clicker.try_send(event) {
+            tracing::warn!(
+                name = self.name.as_deref(),
+                "block is busy handling clicks, dropping click event"
+            );
+        }
+    }
+
+    /// Cancel the block and wait for it to shut down, until `deadline` (if
+    /// any). If it hasn't by then, its task is aborted. If the block
+    /// panicked, the panic is logged rather than propagated, so that the
+    /// remaining blocks are still removed.
+    async fn remove(mut self, deadline: Option<time::Instant>) {
+        self.cancel.cancel();
+        let result = match deadline {
+            Some(deadline) => time::timeout_at(deadline, &mut self.handle).await,
+            None => Ok((&mut self.handle).await),
+        };
+        let result = if let Ok(result) = result {
+            result
+        } else {
+            tracing::warn!("block took too long to shut down, aborting it");
+            self.handle.abort();
+            self.handle.await
+        };
+        if let Err(err) = result {
+            if err.is_panic() {
+                tracing::error!(err = format_args!("{err}"), "block task failed");
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Blocks {
-    inner: Vec<(JoinHandle<()>, CancellationToken, Arc<RwLock<Body>>)>,
+    inner: Vec<BlockHandle>,
     bar_tx: mpsc::Sender<BarMsg>,
+    /// Maximum time to wait for removed blocks to shut down
+    remove_timeout: Duration,
 }
 
 impl Blocks {
-    pub const fn new(bar_tx: mpsc::Sender<BarMsg>) -> Self {
+    pub const fn new(bar_tx: mpsc::Sender<BarMsg>, remove_timeout: Duration) -> Self {
         Self {
             inner: Vec::new(),
             bar_tx,
+            remove_timeout,
         }
     }
 
+    /// Set the maximum time to wait for removed blocks to shut down before
+    /// aborting them.
+    pub fn set_remove_timeout(&mut self, remove_timeout: Duration) {
+        self.remove_timeout = remove_timeout;
+    }
+
+    /// Returns the time by which blocks removed now must have shut down, if
+    /// it's representable.
+    fn remove_deadline(&self) -> Option<time::Instant> {
+        time::Instant::now().checked_add(self.remove_timeout)
+    }
+
     pub async fn remove_all(&mut self) {
-        for (handle, token, _body) in core::mem::take(&mut self.inner) {
-            token.cancel();
-            handle.await.unwrap();
+        let blocks = core::mem::take(&mut self.inner);
+        /* every block is cancelled before waiting for any of them, so they
+         * shut down concurrently rather than continuing to run commands (and
+         * request refreshes nobody will receive) while waiting their turn */
+        for block in &blocks {
+            block.cancel.cancel();
+        }
+        /* blocks shut down concurrently, so they share a deadline */
+        let deadline = self.remove_deadline();
+        for block in blocks {
+            block.remove(deadline).await;
         }
     }
 
@@ -38,33 +134,350 @@ impl Blocks {
     pub fn add_all<B: Iterator<Item = TomlBlock> + ExactSizeIterator>(
         &mut self,
         blocks: B,
-        global_body: Arc<Body>,
-        command_dir: Arc<PathBuf>,
+        global: Arc<Global>,
     ) {
         assert!(self.inner.is_empty());
         let num_blocks = blocks.len();
         for (id, toml) in blocks.enumerate() {
-            let (block, token) = Block::new(
-                toml,
-                Arc::clone(&global_body),
-                Arc::clone(&command_dir),
-                self.bar_tx.clone(),
-                id,
-                num_blocks,
-            );
-            let body = block.body();
-            let handle = task::spawn(async move { block.listen().await });
-            self.inner.push((handle, token, body));
+            let block = self.spawn(toml, Arc::clone(&global), id, num_blocks);
+            self.inner.push(block);
+        }
+    }
+
+    /// Replace all blocks with `blocks`, like [`Blocks::remove_all`] followed
+    /// by [`Blocks::add_all`], except that each running block is kept and
+    /// reconfigured if its counterpart at the same position can be (see
+    /// [`Blocks::try_reconfigure`]).
+    #[allow(clippy::needless_pass_by_value)]
+    pub async fn reconfigure_all<B: Iterator<Item = TomlBlock> + ExactSizeIterator>(
+        &mut self,
+        blocks: B,
+        global: Arc<Global>,
+    ) {
+        let num_blocks = blocks.len();
+        let mut old_blocks = core::mem::take(&mut self.inner).into_iter();
+        let mut new_blocks = Vec::with_capacity(num_blocks);
+        let mut removed = Vec::new();
+        for (id, toml) in blocks.enumerate() {
+            let Some(mut block) = old_blocks.next() else {
+                new_blocks.push(Err(toml));
+                continue;
+            };
+            if Self::try_reconfigure(&mut block, &toml, Arc::clone(&global), id, false) {
+                new_blocks.push(Ok(block));
+            } else {
+                tracing::trace!(id, "restarting block");
+                removed.push(block);
+                new_blocks.push(Err(toml));
+            }
+        }
+        removed.extend(old_blocks);
+
+        /* like in `remove_all`, blocks are removed concurrently, so they
+         * share a deadline. they're removed before any are restarted, so a
+         * block's command never runs alongside that of its replacement */
+        for block in &removed {
+            block.cancel.cancel();
+        }
+        let deadline = self.remove_deadline();
+        for block in removed {
+            block.remove(deadline).await;
+        }
+
+        for (id, block) in new_blocks.into_iter().enumerate() {
+            let block =
+                block.unwrap_or_else(|toml| self.spawn(toml, Arc::clone(&global), id, num_blocks));
+            self.inner.push(block);
+        }
+    }
+
+    /// Apply `toml` to the block named `name`, and execute its command again.
+    /// Like [`Blocks::reconfigure_all`], the block is kept running if it can
+    /// be reconfigured, and otherwise restarted. Returns whether there was
+    /// such a block.
+    pub async fn reload(&mut self, name: &str, toml: TomlBlock, global: Arc<Global>) -> bool {
+        let Some(id) = self
+            .inner
+            .iter()
+            .position(|block| block.name.as_deref() == Some(name))
+        else {
+            return false;
+        };
+
+        if !Self::try_reconfigure(&mut self.inner[id], &toml, Arc::clone(&global), id, true) {
+            tracing::trace!(id, "restarting block");
+            let block = self.inner.remove(id);
+            block.remove(self.remove_deadline()).await;
+            let block = self.spawn(toml, global, id, self.inner.len() + 1);
+            self.inner.insert(id, block);
+        }
+        true
+    }
+
+    /// Reconfigure the running `block` with `toml`, without waiting for it
+    /// to apply it. If `rerun`, it executes its command again.
+    ///
+    /// Returns false if the block can't be reconfigured (see
+    /// [`Block::can_reconfigure`]), if it's still busy with an earlier
+    /// reconfiguration, or if it stopped, in which case it should be
+    /// restarted instead.
+    fn try_reconfigure(
+        block: &mut BlockHandle,
+        toml: &TomlBlock,
+        global: Arc<Global>,
+        id: usize,
+        rerun: bool,
+    ) -> bool {
+        if !Block::can_reconfigure(&block.config.0, toml) {
+            return false;
+        }
+        let reconfigure = Reconfigure {
+            toml: toml.clone(),
+            global,
+            rerun,
+        };
+        match block.config.1.try_send(reconfigure) {
+            Ok(()) => {
+                tracing::trace!(id, rerun, "reconfiguring block");
+                block.name.clone_from(&toml.body.name);
+                block.priority = toml.priority;
+                block.group.clone_from(&toml.group);
+                block.config.0.clone_from(toml);
+                true
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!(id, "block is busy with an earlier reconfiguration");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::warn!(id, "block stopped unexpectedly");
+                false
+            }
         }
     }
 
-    pub fn iter(
+    fn spawn(
         &self,
-    ) -> core::slice::Iter<(JoinHandle<()>, CancellationToken, Arc<RwLock<Body>>)> {
+        toml: TomlBlock,
+        global: Arc<Global>,
+        id: usize,
+        num_blocks: usize,
+    ) -> BlockHandle {
+        let name = toml.body.name.clone();
+        let priority = toml.priority;
+        let group = toml.group.clone();
+        let config = toml.clone();
+        let (block, cancel) = Block::new(toml, global, self.bar_tx.clone(), id, num_blocks);
+        let body = block.body();
+        let reconfigure_tx = block.reconfigurer();
+        let hidden = block.hidden();
+        let initialized = block.initialized();
+        let clicker = block.clicker();
+        let handle = task::spawn(async move { block.listen().await });
+        BlockHandle {
+            handle,
+            cancel,
+            body,
+            name,
+            priority,
+            group,
+            hidden,
+            initialized,
+            config: (config, reconfigure_tx),
+            clicker,
+        }
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, BlockHandle> {
         self.inner.iter()
     }
 
+    pub fn get(&self, idx: usize) -> Option<&BlockHandle> {
+        self.inner.get(idx)
+    }
+
     pub fn len(&self) -> usize {
         self.inner.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns whether every block is initialized.
+    pub fn all_initialized(&self) -> bool {
+        self.inner
+            .iter()
+            .all(|block| block.initialized.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use crate::config::Shell;
+
+    fn global() -> Arc<Global> {
+        Arc::new(Global {
+            body: Body::new(),
+            command_dir: Arc::new(env::temp_dir()),
+            env: Vec::new(),
+            audit_log: None,
+            umask: None,
+            shell: Shell::default(),
+        })
+    }
+
+    fn blocks(num_blocks: usize) -> Vec<TomlBlock> {
+        (0..num_blocks)
+            .map(|idx| {
+                toml::from_str(&format!(
+                    r#"
+                    command = "echo {idx}"
+                    interval = 0.001
+                    "#
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    /// Reload and remove blocks over and over while they keep requesting
+    /// refreshes, each of which reads every body like the bar does.
+    #[tokio::test]
+    async fn remove_while_refreshing() {
+        const NUM_BLOCKS: usize = 8;
+        const NUM_RELOADS: usize = 20;
+
+        let (bar_tx, mut bar_rx) = mpsc::channel(NUM_BLOCKS * 2);
+        let bodies: Arc<RwLock<Vec<Arc<RwLock<Body>>>>> = Arc::default();
+        let bar = task::spawn({
+            let bodies = Arc::clone(&bodies);
+            async move {
+                while let Some(msg) = bar_rx.recv().await {
+                    if let BarMsg::RefreshBlocks = msg {
+                        for body in bodies.read().await.iter() {
+                            let _body = body.read().await;
+                            task::yield_now().await;
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut blocks = Blocks::new(bar_tx, Duration::from_secs(1));
+        time::timeout(Duration::from_secs(10), async {
+            for reload in 0..NUM_RELOADS {
+                if reload % 2 == 0 {
+                    blocks.remove_all().await;
+                    blocks.add_all(self::blocks(NUM_BLOCKS).into_iter(), global());
+                } else {
+                    blocks
+                        .reconfigure_all(self::blocks(NUM_BLOCKS).into_iter(), global())
+                        .await;
+                }
+                *bodies.write().await =
+                    blocks.iter().map(|block| Arc::clone(&block.body)).collect();
+                assert_eq!(blocks.len(), NUM_BLOCKS);
+                time::sleep(Duration::from_millis(5)).await;
+            }
+            blocks.remove_all().await;
+        })
+        .await
+        .expect("removing blocks must not deadlock");
+        assert!(blocks.is_empty());
+
+        drop(blocks);
+        bar.await.unwrap();
+    }
+
+    /// Sets its flag once dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// A block which never shuts down once cancelled is aborted after the
+    /// deadline, rather than waited for forever.
+    #[tokio::test(start_paused = true)]
+    async fn remove_aborts_block_ignoring_cancellation() {
+        const TIMEOUT: Duration = Duration::from_secs(3);
+
+        let (bar_tx, _bar_rx) = mpsc::channel(2);
+        let mut blocks = Blocks::new(bar_tx, TIMEOUT);
+        blocks.add_all(self::blocks(1).into_iter(), global());
+        let mut block = blocks.inner.pop().unwrap();
+
+        /* replace the block's task with one which ignores cancellation */
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(Arc::clone(&dropped));
+        block.handle.abort();
+        block.handle = task::spawn(async move {
+            let _flag = flag;
+            core::future::pending::<()>().await;
+        });
+
+        let start = time::Instant::now();
+        block.remove(blocks.remove_deadline()).await;
+        assert!(start.elapsed() >= TIMEOUT);
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    /// A block whose task stopped can't be reconfigured, so it's restarted.
+    #[tokio::test]
+    async fn reconfigure_restarts_stopped_block() {
+        let (bar_tx, _bar_rx) = mpsc::channel(2);
+        let mut blocks = Blocks::new(bar_tx, Duration::from_secs(1));
+        blocks.add_all(self::blocks(1).into_iter(), global());
+        blocks.inner[0].handle.abort();
+        while !blocks.inner[0].handle.is_finished() {
+            task::yield_now().await;
+        }
+
+        blocks
+            .reconfigure_all(self::blocks(1).into_iter(), global())
+            .await;
+        assert!(!blocks.inner[0].handle.is_finished());
+        blocks.remove_all().await;
+    }
+
+    /// A block still busy with an earlier reconfiguration is restarted,
+    /// rather than waited for.
+    #[tokio::test]
+    async fn reload_restarts_busy_block() {
+        let mut toml = self::blocks(1).remove(0);
+        toml.body.name = Some("busy".into());
+
+        let (bar_tx, _bar_rx) = mpsc::channel(2);
+        let mut blocks = Blocks::new(bar_tx, Duration::from_secs(1));
+        blocks.add_all([toml.clone()].into_iter(), global());
+
+        /* replace the block's channel with one nobody receives from, and
+         * fill it up */
+        let (reconfigure_tx, _reconfigure_rx) = mpsc::channel(1);
+        reconfigure_tx
+            .try_send(Reconfigure {
+                toml: toml.clone(),
+                global: global(),
+                rerun: false,
+            })
+            .unwrap();
+        blocks.inner[0].config.1 = reconfigure_tx.clone();
+
+        let reloaded = time::timeout(
+            Duration::from_secs(5),
+            blocks.reload("busy", toml, global()),
+        )
+        .await
+        .expect("reloading a busy block must not wait for it");
+        assert!(reloaded);
+        assert!(!blocks.inner[0].config.1.same_channel(&reconfigure_tx));
+        blocks.remove_all().await;
+    }
 }
@@ -0,0 +1,59 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use tracing::{span, Level};
+
+use std::io;
+use std::thread;
+
+use crate::bar::{BarHandle, BarMsg};
+use crate::protocol::ClickEventReader;
+
+/// Read click events from standard input on a separate thread, sending each
+/// to `bar` as [`BarMsg::Click`] and sending [`BarMsg::ClickEof`] once it's
+/// closed.
+///
+/// A thread is used rather than a task, since a task blocked on reading
+/// standard input would keep the runtime from shutting down.
+///
+/// # Errors
+///
+/// Spawning the thread may fail.
+pub fn spawn_reader(bar: BarHandle) -> io::Result<()> {
+    thread::Builder::new()
+        .name("click_reader".to_owned())
+        .spawn(move || {
+            let span = span!(Level::INFO, "click_reader");
+            let _enter = span.enter();
+
+            for event in ClickEventReader::new(io::stdin().lock()) {
+                match event {
+                    Ok(event) => {
+                        tracing::trace!(
+                            name = event.name.as_deref(),
+                            instance = event.instance.as_deref(),
+                            button = event.button,
+                            "received click event"
+                        );
+                        if bar.blocking_send(BarMsg::Click(event)).is_err() {
+                            /* the bar shut down */
+                            return;
+                        }
+                    }
+                    Err(err) if err.is_data() || err.is_syntax() => {
+                        tracing::warn!("failed to parse click event, skipping it: {err}");
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to read click events: {err}");
+                        break;
+                    }
+                }
+            }
+
+            tracing::trace!("standard input closed");
+            /* the bar may have already shut down, in which case there's
+             * nothing left to do */
+            let _ = bar.blocking_send(BarMsg::ClickEof);
+        })?;
+    Ok(())
+}
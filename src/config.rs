@@ -5,23 +5,117 @@ use anyhow::{anyhow, Context};
 use cowstr::CowStr;
 use semver::{Version, VersionReq};
 use serde_derive::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::time;
 use tracing::{span, Level};
 
-use core::str;
-use std::fs::OpenOptions;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::mem;
+use core::time::Duration;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
 use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
-use crate::protocol::{Body, Header, Signal};
+use crate::block;
+use crate::protocol::{self, Align, Body, BodyField, Header, Markup, Signal};
 
 /// Bar configuration, directly deserialized.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-// TODO: don't deny unknown fields for compatibility, but do warn about them
-#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct TomlBar {
     command_dir: Option<String>,
-    #[serde(default = "TomlBar::default_smolbar_version_req")]
-    smolbar_version: VersionReq,
+    env_file: Option<String>,
+    /// Paths of files whose blocks are appended to those of the bar
+    #[serde(default)]
+    include: Vec<String>,
+    /// Environment variables set for all commands, overriding those of
+    /// `env_file`
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    pidfile: Option<String>,
+    /// Path of the socket through which a running smolbar is controlled
+    control_socket: Option<String>,
+    audit_log: Option<String>,
+    /// Command to run once on startup, before any block starts
+    pre_command: Option<String>,
+    /// Time, in seconds, after which `pre_command` is killed
+    #[serde(default = "TomlBar::default_pre_command_timeout")]
+    pre_command_timeout: f32,
+    /// Whether to refuse to start if `pre_command` fails
+    #[serde(default)]
+    pre_command_required: bool,
+    /// Command to run once on clean shutdown, after every block stopped
+    post_command: Option<String>,
+    /// Time, in seconds, after which `post_command` is killed
+    #[serde(default = "TomlBar::default_post_command_timeout")]
+    post_command_timeout: f32,
+    /// Umask of every block's command which doesn't define its own
+    pub umask: Option<Umask>,
+    /// Shell through which every block's command which doesn't define its
+    /// own, as well as `pre_command` and `post_command`, is executed
+    #[serde(default)]
+    pub shell: Shell,
+    /// Markup applied to every block which doesn't define its own
+    default_markup: Option<Markup>,
+    /// Whether to write compact JSON, rather than pretty JSON. If undefined,
+    /// the header is compact and blocks are pretty.
+    pub compact_output: Option<bool>,
+    /// Number of spaces to indent pretty JSON with
+    #[serde(default = "TomlBar::default_output_indent")]
+    pub output_indent: usize,
+    /// Maximum total number of characters in the `full_text` of all blocks,
+    /// above which the lowest priority blocks are omitted
+    pub max_total_length: Option<usize>,
+    /// Whether to draw powerline chevrons between blocks, colored by their
+    /// backgrounds
+    #[serde(default)]
+    pub powerline: bool,
+    /// Whether to set each block's `name` to identify it in the output, for
+    /// debugging
+    #[serde(default)]
+    pub debug_names: bool,
+    /// Whether to send blocks in the reverse of the order they're defined
+    #[serde(default)]
+    pub reverse: bool,
+    /// Dim the bar after a period without activity
+    pub idle_dim: Option<IdleDim>,
+    /// Maximum time, in seconds, to wait for each removed block to shut down
+    /// before aborting it
+    #[serde(default = "TomlBar::default_block_shutdown_timeout")]
+    pub block_shutdown_timeout: f32,
+    /// Maximum time, in seconds, the first refresh waits for every block to
+    /// be initialized
+    startup_timeout: Option<f32>,
+    /// How blocks are replaced when reloading
+    #[serde(default)]
+    pub reload: ReloadPolicy,
+    /// What to do when standard input is closed, if reading click events
+    #[serde(default)]
+    pub click_eof: ClickEofPolicy,
+    /// Operating system signal to send all blocks when received, even if
+    /// they're unchanged
+    pub refresh_signal: Option<Signal>,
+    /// Operating system signal to switch between verbose and terse logs when
+    /// received
+    pub log_signal: Option<Signal>,
+    /// Operating system signal to reload the configuration when received. If
+    /// undefined, it's the header's `cont_signal`.
+    pub reload_signal: Option<Signal>,
+    /// Operating system signal to shut down when received. If undefined,
+    /// it's the header's `stop_signal`.
+    pub shutdown_signal: Option<Signal>,
+    /// Whether to listen for changed `cont_signal`, `stop_signal`,
+    /// `reload_signal`, `shutdown_signal`, `refresh_signal`, and `log_signal`
+    /// when reloading, rather than only warning about them
+    #[serde(default)]
+    pub reload_signals: bool,
+    #[serde(default = "TomlBar::default_smolbar_version")]
+    smolbar_version: VersionCheck,
     /// Configured [`Header`]
     #[serde(default = "Header::default")]
     pub header: Header,
@@ -31,11 +125,67 @@ pub struct TomlBar {
     /// The bar's configured [blocks](TomlBlock)
     #[serde(default = "Vec::new", rename = "block")]
     pub blocks: Vec<TomlBlock>,
+    /// Named configurations merged over the rest when selected (see
+    /// [`merge_profile`])
+    #[serde(default)]
+    profiles: toml::Table,
+    /// Fields which aren't known, which are warned about rather than
+    /// rejected, for compatibility with other versions of smolbar
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
 }
 
 impl TomlBar {
-    pub const fn default_smolbar_version_req() -> VersionReq {
-        VersionReq::STAR
+    /// Default value of [`TomlBar::output_indent`].
+    pub const DEFAULT_OUTPUT_INDENT: usize = 2;
+
+    pub const fn default_smolbar_version() -> VersionCheck {
+        VersionCheck::Req(VersionReq::STAR)
+    }
+
+    const fn default_block_shutdown_timeout() -> f32 {
+        5.0
+    }
+
+    const fn default_pre_command_timeout() -> f32 {
+        10.0
+    }
+
+    const fn default_post_command_timeout() -> f32 {
+        10.0
+    }
+
+    /// Returns the signal which reloads the configuration, which is the
+    /// header's `cont_signal` unless `reload_signal` is defined.
+    pub fn reload_signal(&self) -> Signal {
+        self.reload_signal
+            .or(self.header.cont_signal)
+            .unwrap_or(Header::DEFAULT_CONT_SIG)
+    }
+
+    /// Returns the signal which shuts down, which is the header's
+    /// `stop_signal` unless `shutdown_signal` is defined.
+    pub fn shutdown_signal(&self) -> Signal {
+        self.shutdown_signal
+            .or(self.header.stop_signal)
+            .unwrap_or(Header::DEFAULT_STOP_SIG)
+    }
+
+    /// Returns the configured `block_shutdown_timeout`.
+    pub fn block_shutdown_timeout(&self) -> Duration {
+        /* this was validated when reading the config */
+        Duration::try_from_secs_f32(self.block_shutdown_timeout).unwrap_or_default()
+    }
+
+    /// Returns the configured `startup_timeout`, if any.
+    pub fn startup_timeout(&self) -> Option<Duration> {
+        /* this was validated when reading the config */
+        self.startup_timeout
+            .map(|secs| Duration::try_from_secs_f32(secs).unwrap_or_default())
+    }
+
+    const fn default_output_indent() -> usize {
+        Self::DEFAULT_OUTPUT_INDENT
     }
 
     pub fn current_smolbar_version() -> Version {
@@ -43,14 +193,379 @@ impl TomlBar {
             .parse()
             .expect("Cargo correctly sets version information")
     }
+
+    /// Expand environment variables (see [`expand_env`]) in the paths of the
+    /// bar and its blocks. Other strings are left as is, including commands,
+    /// which are left for the shell to expand when they're executed, and
+    /// strings like `full_text`.
+    fn expand_env(&mut self) -> anyhow::Result<()> {
+        for (field, value) in [
+            ("command_dir", &mut self.command_dir),
+            ("env_file", &mut self.env_file),
+            ("pidfile", &mut self.pidfile),
+            ("control_socket", &mut self.control_socket),
+            ("audit_log", &mut self.audit_log),
+        ] {
+            if let Some(value) = value {
+                *value = expand_env(value).with_context(|| format!("failed to expand {field}"))?;
+            }
+        }
+        for (id, block) in self.blocks.iter_mut().enumerate() {
+            if let Some(Watch::Path(ref mut path)) = block.watch {
+                *path = expand_env(path)
+                    .with_context(|| format!("failed to expand watch of block {id}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn about blocks which share a signal with the bar, since sending
+    /// that signal would also reload or stop the bar.
+    fn warn_signal_clashes(&self) {
+        for (id, block) in self.blocks.iter().enumerate() {
+            if let Some(signal) = block.signal {
+                for (bar_signal, field, effect) in [
+                    (
+                        self.reload_signal(),
+                        if self.reload_signal.is_some() {
+                            "reload_signal"
+                        } else {
+                            "header.cont_signal"
+                        },
+                        "reload the configuration",
+                    ),
+                    (
+                        self.shutdown_signal(),
+                        if self.shutdown_signal.is_some() {
+                            "shutdown_signal"
+                        } else {
+                            "header.stop_signal"
+                        },
+                        "stop smolbar",
+                    ),
+                ] {
+                    if signal == bar_signal {
+                        tracing::warn!(
+                            id,
+                            signal = format_args!("{signal}"),
+                            "block signal is also {field}, so it will also {effect}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check every configured color, since swaybar silently ignores those
+    /// which are invalid.
+    fn validate_colors(&self) -> anyhow::Result<()> {
+        if let Some((field, color)) = self.body.invalid_color() {
+            return Err(anyhow!(
+                r#"invalid {field} "{color}" (expected #RRGGBB or #RRGGBBAA)"#
+            ));
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            if let Some((field, color)) = block.body.invalid_color() {
+                return Err(anyhow!(
+                    r#"block {id} has invalid {field} "{color}" (expected #RRGGBB or #RRGGBBAA)"#
+                ));
+            }
+            for range in &block.color_when {
+                if !protocol::is_valid_color(&range.color) {
+                    return Err(anyhow!(
+                        r#"block {id} has invalid color_when color "{color}" (expected #RRGGBB or #RRGGBBAA)"#,
+                        color = range.color
+                    ));
+                }
+            }
+            if let Some(ref color) = block.separator_color {
+                if !protocol::is_valid_color(color) {
+                    return Err(anyhow!(
+                        r#"block {id} has invalid separator_color "{color}" (expected #RRGGBB or #RRGGBBAA)"#
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn about every field which isn't known, along with the block and
+    /// table it's in, if any. These may be typos, or fields of another version
+    /// of smolbar.
+    fn warn_unknown(&self) {
+        for field in self.unknown.keys() {
+            tracing::warn!(field, "unknown field, ignoring it");
+        }
+        if let Some(ref idle_dim) = self.idle_dim {
+            for field in idle_dim.unknown.keys() {
+                tracing::warn!(table = "idle_dim", field, "unknown field, ignoring it");
+            }
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for field in block.unknown.keys() {
+                tracing::warn!(id, field, "unknown field of block, ignoring it");
+            }
+            for (table, unknown) in block.unknown_tables() {
+                for field in unknown.keys() {
+                    tracing::warn!(id, table, field, "unknown field of block, ignoring it");
+                }
+            }
+        }
+    }
+
+    /// Check values which swaybar wouldn't, or which would otherwise cause
+    /// errors later.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.shell == Shell::Command(Vec::new()) {
+            return Err(anyhow!("shell is empty (expected a program)"));
+        }
+        self.validate_colors()?;
+        /* durations aren't checked by anything before they're needed, so
+         * check them now */
+        for (id, block) in self.blocks.iter().enumerate() {
+            if block.shell == Some(Shell::Command(Vec::new())) {
+                return Err(anyhow!(
+                    "block {id} has an empty shell (expected a program)"
+                ));
+            }
+            if let Some(ref sparkline) = block.sparkline {
+                if sparkline.samples == 0 {
+                    return Err(anyhow!(
+                        "block {id} has invalid sparkline.samples 0 (expected at least 1)"
+                    ));
+                }
+            }
+            if block.watch == Some(Watch::Command(true)) && block.command.is_none() {
+                return Err(anyhow!(
+                    "block {id} watches its command, but has no command"
+                ));
+            }
+            if let Some(ref trend) = block.trend {
+                if trend.deadband.is_nan() || trend.deadband < 0.0 {
+                    return Err(anyhow!(
+                        "block {id} has invalid trend.deadband {deadband} (expected a non-negative number)",
+                        deadband = trend.deadband
+                    ));
+                }
+            }
+            if block.format.is_some() && block.full_text_end.is_some() {
+                return Err(anyhow!(
+                    "block {id} has both format and full_text_end (expected at most one)"
+                ));
+            }
+            if block.timeout == Some(0.0) {
+                return Err(anyhow!(
+                    "block {id} has invalid timeout 0 (expected a positive number of seconds)"
+                ));
+            }
+            for (field, secs) in [
+                ("timeout", block.timeout),
+                ("kill_grace", Some(block.kill_grace)),
+            ] {
+                if let Some(secs) = secs {
+                    if Duration::try_from_secs_f32(secs).is_err() {
+                        return Err(anyhow!(
+                            "block {id} has invalid {field} {secs} (expected a non-negative number of seconds)"
+                        ));
+                    }
+                }
+            }
+        }
+
+        if Duration::try_from_secs_f32(self.block_shutdown_timeout).is_err() {
+            return Err(anyhow!(
+                "invalid block_shutdown_timeout {secs} (expected a non-negative number of seconds)",
+                secs = self.block_shutdown_timeout
+            ));
+        }
+
+        for (field, secs) in [
+            ("pre_command_timeout", self.pre_command_timeout),
+            ("post_command_timeout", self.post_command_timeout),
+        ]
+        .into_iter()
+        .chain(self.startup_timeout.map(|secs| ("startup_timeout", secs)))
+        {
+            if Duration::try_from_secs_f32(secs).is_err() {
+                return Err(anyhow!(
+                    "invalid {field} {secs} (expected a non-negative number of seconds)"
+                ));
+            }
+        }
+
+        if let Some(ref idle_dim) = self.idle_dim {
+            if Duration::try_from_secs_f32(idle_dim.timeout).is_err() {
+                return Err(anyhow!(
+                    "idle_dim has invalid timeout {timeout} (expected a non-negative number of seconds)",
+                    timeout = idle_dim.timeout
+                ));
+            }
+            if !(0.0..=1.0).contains(&idle_dim.opacity) {
+                return Err(anyhow!(
+                    "idle_dim has invalid opacity {opacity} (expected a number from 0 to 1)",
+                    opacity = idle_dim.opacity
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Value of `smolbar_version`, which is either a requirement on the current
+/// version, or `false` to not check it at all.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged, try_from = "RawVersionCheck")]
+pub enum VersionCheck {
+    /// If false, the version isn't checked. If true, it's checked against
+    /// the default requirement.
+    Enabled(bool),
+    /// The current version must satisfy this requirement
+    Req(VersionReq),
+}
+
+/// [`VersionCheck`] before its requirement is parsed, so that an invalid
+/// requirement is reported as such rather than as matching neither variant.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawVersionCheck {
+    Enabled(bool),
+    Req(String),
+}
+
+impl TryFrom<RawVersionCheck> for VersionCheck {
+    type Error = semver::Error;
+
+    fn try_from(raw: RawVersionCheck) -> Result<Self, Self::Error> {
+        match raw {
+            RawVersionCheck::Enabled(enabled) => Ok(Self::Enabled(enabled)),
+            RawVersionCheck::Req(req) => req.parse().map(Self::Req),
+        }
+    }
+}
+
+impl VersionCheck {
+    /// Returns the requirement the current version must satisfy, unless the
+    /// check is disabled.
+    pub fn requirement(&self) -> Option<VersionReq> {
+        match self {
+            Self::Enabled(false) => None,
+            Self::Enabled(true) => Some(VersionReq::STAR),
+            Self::Req(req) => Some(req.clone()),
+        }
+    }
+}
+
+/// How running blocks are replaced when the configuration is reloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReloadPolicy {
+    /// Restart every block
+    #[default]
+    Full,
+    /// Keep running each block whose command, `interval`, and `signal` are
+    /// unchanged, applying the rest of its new configuration
+    Soft,
+}
+
+/// What to do when standard input, through which click events are received,
+/// is closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickEofPolicy {
+    /// Stop reading click events, but keep running
+    #[default]
+    Ignore,
+    /// Shut down, since the bar is most likely gone
+    Shutdown,
+}
+
+/// Value of `watch`, which is either whether to watch the block's command,
+/// or the path of a file to watch.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Watch {
+    /// If true, the block's command is watched
+    Command(bool),
+    /// Path of the watched file, relative to the block's `command_dir`
+    Path(String),
+}
+
+/// Value of `shell`, which is either whether to execute commands through
+/// `sh -c`, or the program and arguments of a shell to use instead.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Shell {
+    /// If true, commands are executed through `sh -c`. Otherwise, they're
+    /// executed directly, as paths of programs.
+    Default(bool),
+    /// Program and arguments, after which the command is passed as one more
+    /// argument
+    Command(Vec<String>),
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::Default(true)
+    }
+}
+
+impl Shell {
+    /// Returns the program and arguments to pass a command to, or `None` if
+    /// commands are executed directly.
+    pub fn argv(&self) -> Option<Vec<&str>> {
+        match self {
+            Self::Default(false) => None,
+            Self::Default(true) => Some(vec!["sh", "-c"]),
+            Self::Command(argv) => Some(argv.iter().map(String::as_str).collect()),
+        }
+    }
+
+    /// Returns the program which executing `command` through the shell
+    /// depends on, if it can tell. Executed directly, this is `command`
+    /// itself. Through a shell, it's the first word of `command`, but only
+    /// if it's a path, since otherwise it may be a builtin.
+    pub fn program<'a>(&self, command: &'a str) -> Option<&'a str> {
+        if self.argv().is_none() {
+            return Some(command);
+        }
+        command
+            .split_whitespace()
+            .next()
+            .filter(|program| program.contains('/'))
+    }
+}
+
+/// Which output streams of a block's command its body is read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capture {
+    /// Standard output, discarding standard error
+    #[default]
+    Stdout,
+    /// Standard error, discarding standard output
+    Stderr,
+    /// Standard error redirected to standard output, like `2>&1`
+    Both,
 }
 
 /// Block configuration, directly deserialized.
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
 pub struct TomlBlock {
+    /// Whether the block is shown at all. A disabled block is left out of
+    /// the bar, as if it weren't configured.
+    #[serde(default = "TomlBlock::default_enabled")]
+    pub enabled: bool,
     /// Command to execute to configure body at `immediate` scope
     pub command: Option<String>,
+    /// Command to execute when the block is clicked, before `command`
+    pub on_click: Option<String>,
+    /// Line of command output which ends a multi-line `full_text`
+    pub full_text_end: Option<CowStr>,
+    /// Template of `full_text`, filled by named values the command prints,
+    /// rather than reading each property from a line
+    pub format: Option<Format>,
     /// String prefixing `full_text`
     pub prefix: Option<CowStr>,
     /// String appended to `full_text`
@@ -60,64 +575,820 @@ pub struct TomlBlock {
     /// If the interval is negative, overflows
     /// [`Duration`](core::time::Duration), or is not finite, it is ignored.
     pub interval: Option<f32>,
+    /// Whether to honor an `interval` under a millisecond, rather than
+    /// clamping it to a millisecond
+    #[serde(default)]
+    pub precise_interval: bool,
     /// Operating system signal to refresh the block when received
     pub signal: Option<Signal>,
+    /// Time, in seconds, after which `command` is terminated
+    pub timeout: Option<f32>,
+    /// Time, in seconds, to wait after terminating `command` before killing
+    /// it
+    #[serde(default = "TomlBlock::default_kill_grace")]
+    pub kill_grace: f32,
+    /// Maximum CPU time, in seconds, that `command` may use
+    pub rlimit_cpu: Option<u64>,
+    /// Maximum size, in bytes, of the address space of `command`
+    pub rlimit_mem: Option<u64>,
+    /// Umask of `command`, overriding that in global scope
+    pub umask: Option<Umask>,
+    /// Environment variables set for `command` and `on_click`, overriding
+    /// those in global scope
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Shell through which `command` and `on_click` are executed,
+    /// overriding that in global scope
+    pub shell: Option<Shell>,
+    /// File whose changes cause `command` to be executed again
+    pub watch: Option<Watch>,
+    /// Output streams of `command` which the body is read from
+    #[serde(default)]
+    pub capture: Capture,
+    /// Whether to refresh the block on startup, rather than waiting for
+    /// `interval` or `signal`
+    #[serde(default = "TomlBlock::default_run_on_start")]
+    pub run_on_start: bool,
+    /// Maximum number of characters in `full_text`, including `prefix`,
+    /// `postfix`, and `ellipsis`. With Pango markup, only displayed characters
+    /// count
+    pub max_length: Option<usize>,
+    /// String appended to `full_text` if it was truncated to `max_length`
+    pub ellipsis: Option<CowStr>,
+    /// Size, in bytes, of the serialized body above which a warning is logged
+    #[serde(default = "TomlBlock::default_max_body_size")]
+    pub max_body_size: usize,
+    /// Whether to truncate `full_text` until the body fits in `max_body_size`
+    #[serde(default)]
+    pub truncate_body: bool,
+    /// Set `urgent` if the number at the start of `full_text` crosses a
+    /// threshold
+    pub urgent_when: Option<Threshold>,
+    /// Set `color` to that of the first range the number at the start of
+    /// `full_text` falls in
+    #[serde(default = "Vec::new")]
+    pub color_when: Vec<ColorRange>,
+    /// Derive `short_text` from `full_text` if no scope defines it
+    pub auto_short: Option<AutoShort>,
+    /// Replace `full_text` with a sparkline of the numbers it recently
+    /// started with
+    pub sparkline: Option<Sparkline>,
+    /// Format the number at the start of `full_text` with unit prefixes
+    pub scale: Option<Scale>,
+    /// Number of digits after the decimal point of numbers formatted by
+    /// `scale`
+    #[serde(default = "TomlBlock::default_scale_precision")]
+    pub scale_precision: usize,
+    /// Pad the number at the start of `full_text` to a fixed width
+    pub pad: Option<Pad>,
+    /// Mark whether the number at the start of `full_text` rose or fell
+    pub trend: Option<Trend>,
+    /// Priority of the block when omitting blocks to fit in
+    /// [`TomlBar::max_total_length`]. Lower priority blocks are omitted
+    /// first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Text appended to `full_text` and `short_text` as a separator, in place
+    /// of the one drawn by swaybar
+    pub separator_text: Option<CowStr>,
+    /// Color of `separator_text`, if `markup` is "pango"
+    pub separator_color: Option<CowStr>,
+    /// Output used in place of that of the command if it fails
+    pub fallback_text: Option<CowStr>,
+    /// Whether to ignore blank lines at the end of the command's output
+    #[serde(default = "TomlBlock::default_trim_blank_lines")]
+    pub trim_blank_lines: bool,
+    /// Prepended to `full_text` before `prefix`, and shown even if the command
+    /// succeeds without printing anything
+    pub icon: Option<CowStr>,
+    /// Whether to send the blocks every time the command finishes, even if
+    /// nothing changed
+    #[serde(default)]
+    pub pulse: bool,
+    /// Whether `command` keeps running, writing a new body whenever it
+    /// changes, rather than being executed for each refresh
+    #[serde(default)]
+    pub persistent: bool,
+    /// Whether to mark the block urgent while `command` fails
+    #[serde(default)]
+    pub urgent_on_error: bool,
+    /// Whether to replace invalid UTF-8 in the output of `command`, rather
+    /// than discarding the output
+    #[serde(default)]
+    pub lossy: bool,
+    /// Whether to escape `full_text` printed by `command` if `markup` is
+    /// "pango", so that it's displayed literally
+    #[serde(default)]
+    pub escape_pango: bool,
+    /// Whether to keep showing the block if its command succeeds without
+    /// printing anything, rather than hiding it
+    #[serde(default)]
+    pub keep_empty: bool,
+    /// Whether to draw no separator and leave no gap after the block if its
+    /// `full_text` is empty
+    #[serde(default)]
+    pub collapse_empty: bool,
+    /// Name of the group the block belongs to. No separator is drawn between
+    /// adjacent blocks of the same group.
+    pub group: Option<CowStr>,
+    /// Fields of the body whose changes cause a refresh. If undefined, all of
+    /// them do.
+    pub significant_fields: Option<Vec<BodyField>>,
+    /// Range of characters that `full_text` is expected to start with, like
+    /// the icons of an icon font. A warning is logged if it doesn't.
+    pub expect_glyph_in: Option<GlyphRange>,
 
     /// Body configured at `local` scope
     #[serde(flatten)]
     pub body: Body,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+
+    /// Directory to execute `command` in, overriding [`Config::command_dir`]
+    #[serde(skip)]
+    pub command_dir: Option<Arc<PathBuf>>,
+}
+
+impl TomlBlock {
+    /// Default value of [`TomlBlock::ellipsis`].
+    pub const DEFAULT_ELLIPSIS: &'static str = "…";
+    /// Default value of [`TomlBlock::max_body_size`].
+    pub const DEFAULT_MAX_BODY_SIZE: usize = 4096;
+
+    const fn default_max_body_size() -> usize {
+        Self::DEFAULT_MAX_BODY_SIZE
+    }
+
+    const fn default_scale_precision() -> usize {
+        1
+    }
+
+    const fn default_kill_grace() -> f32 {
+        1.0
+    }
+
+    const fn default_run_on_start() -> bool {
+        true
+    }
+
+    const fn default_trim_blank_lines() -> bool {
+        true
+    }
+
+    const fn default_enabled() -> bool {
+        true
+    }
+
+    /// Returns the fields which aren't known of each table in the block,
+    /// paired with the table's key.
+    fn unknown_tables(&self) -> Vec<(&'static str, &toml::Table)> {
+        let mut tables = Vec::new();
+        if let Some(ref urgent_when) = self.urgent_when {
+            tables.push(("urgent_when", &urgent_when.unknown));
+        }
+        for range in &self.color_when {
+            tables.push(("color_when", &range.unknown));
+        }
+        if let Some(ref auto_short) = self.auto_short {
+            tables.push(("auto_short", &auto_short.unknown));
+        }
+        if let Some(ref sparkline) = self.sparkline {
+            tables.push(("sparkline", &sparkline.unknown));
+        }
+        if let Some(ref pad) = self.pad {
+            tables.push(("pad", &pad.unknown));
+        }
+        if let Some(ref trend) = self.trend {
+            tables.push(("trend", &trend.unknown));
+        }
+        tables
+    }
+}
+
+/// Configuration for dimming the bar when idle.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdleDim {
+    /// Time, in seconds, without activity after which the bar is dimmed
+    pub timeout: f32,
+    /// Opacity multiplied into the colors of dimmed blocks, from 0 to 1
+    pub opacity: f32,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+/// Numeric threshold, crossed if a value is below `below` or above `above`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Threshold {
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+impl Threshold {
+    /// Returns whether `value` crosses the threshold.
+    pub fn is_crossed(&self, value: f64) -> bool {
+        self.below.map_or(false, |below| value < below)
+            || self.above.map_or(false, |above| value > above)
+    }
+}
+
+/// Numeric range mapped to a color.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColorRange {
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    /// Color in #RRGGBBAA or #RRGGBB notation
+    pub color: CowStr,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+impl ColorRange {
+    /// Returns whether `value` is in the range. A range without bounds
+    /// contains every value.
+    pub fn contains(&self, value: f64) -> bool {
+        self.below.map_or(true, |below| value < below)
+            && self.above.map_or(true, |above| value > above)
+    }
+}
+
+/// Inclusive range of characters, written as `"U+E000..U+F8FF"`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GlyphRange {
+    pub start: char,
+    pub end: char,
+}
+
+impl GlyphRange {
+    /// Returns whether `c` is in the range.
+    pub fn contains(self, c: char) -> bool {
+        (self.start..=self.end).contains(&c)
+    }
+}
+
+impl TryFrom<String> for GlyphRange {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        fn codepoint(s: &str) -> Option<char> {
+            let hex = s.trim().strip_prefix("U+")?;
+            char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+        }
+
+        let invalid = || format!(r#"invalid glyph range "{range}" (expected U+XXXX..U+XXXX)"#);
+        let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+        let start = codepoint(start).ok_or_else(invalid)?;
+        let end = codepoint(end).ok_or_else(invalid)?;
+        if start > end {
+            return Err(format!(
+                r#"glyph range "{range}" is empty (start is after end)"#
+            ));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl From<GlyphRange> for String {
+    fn from(range: GlyphRange) -> Self {
+        format!(
+            "U+{:04X}..U+{:04X}",
+            u32::from(range.start),
+            u32::from(range.end)
+        )
+    }
+}
+
+/// File mode creation mask, written as an octal string like `"022"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Umask(u32);
+
+impl TryFrom<String> for Umask {
+    type Error = String;
+
+    fn try_from(umask: String) -> Result<Self, Self::Error> {
+        match u32::from_str_radix(&umask, 8) {
+            Ok(mask) if mask <= 0o777 && !umask.starts_with('+') => Ok(Self(mask)),
+            _ => Err(format!(
+                r#"invalid umask "{umask}" (expected octal from 000 to 777)"#
+            )),
+        }
+    }
+}
+
+impl From<Umask> for String {
+    fn from(umask: Umask) -> Self {
+        format!("{:03o}", umask.0)
+    }
+}
+
+/// Template of `full_text`, written as a string like `"{icon} {percent}%"`.
+/// Each placeholder is replaced by the value of that name, and `{{` and `}}`
+/// are literal braces.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Format {
+    /// Template as written
+    template: String,
+    /// Literal text and placeholders of the template, in order
+    segments: Vec<Segment>,
+}
+
+/// Part of a [`Format`].
+#[derive(Clone, Debug)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+impl Format {
+    /// Returns the template with each placeholder replaced by its value in
+    /// `values`. A placeholder without a value is left empty, and passed to
+    /// `missing`.
+    pub fn render<'a>(
+        &'a self,
+        values: &BTreeMap<&str, &str>,
+        mut missing: impl FnMut(&'a str),
+    ) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Placeholder(name) => match values.get(name.as_str()) {
+                    Some(value) => rendered.push_str(value),
+                    None => missing(name),
+                },
+            }
+        }
+        rendered
+    }
+}
+
+impl TryFrom<String> for Format {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => {
+                                return Err(format!(
+                                    r#"format "{template}" has an unclosed placeholder (write "{{{{" for a literal brace)"#
+                                ));
+                            }
+                            Some(c) => name.push(c),
+                        }
+                    }
+                    let name = name.trim();
+                    if name.is_empty() {
+                        return Err(format!(r#"format "{template}" has an empty placeholder"#));
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Placeholder(name.to_owned()));
+                }
+                '}' => {
+                    return Err(format!(
+                        r#"format "{template}" has an unopened placeholder (write "}}}}" for a literal brace)"#
+                    ));
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self { template, segments })
+    }
+}
+
+impl From<Format> for String {
+    fn from(format: Format) -> Self {
+        format.template
+    }
+}
+
+/// Configuration for deriving `short_text` from `full_text`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutoShort {
+    /// Maximum number of characters in the derived `short_text`, including
+    /// `ellipsis`
+    pub max: usize,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+/// Configuration for rendering recent values as a sparkline.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sparkline {
+    /// Maximum number of recent values to display
+    pub samples: usize,
+    /// Value displayed as the lowest bar. If undefined, it's the lowest
+    /// recent value.
+    pub min: Option<f64>,
+    /// Value displayed as the highest bar. If undefined, it's the highest
+    /// recent value.
+    pub max: Option<f64>,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+impl Sparkline {
+    /// Bars of increasing height, for each level of the sparkline.
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    /// Render `values` as a bar each, scaled between `min` and `max`.
+    pub fn render<'a, V>(&self, values: V) -> String
+    where
+        V: Iterator<Item = &'a f64> + Clone,
+    {
+        let min = self
+            .min
+            .unwrap_or_else(|| values.clone().copied().fold(f64::INFINITY, f64::min));
+        let max = self
+            .max
+            .unwrap_or_else(|| values.clone().copied().fold(f64::NEG_INFINITY, f64::max));
+        values
+            .map(|value| {
+                /* if every value is the same, there's nothing to scale */
+                let level = if max > min {
+                    ((value - min) / (max - min)).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let last = Self::BARS.len() - 1;
+                #[allow(
+                    clippy::cast_possible_truncation,
+                    clippy::cast_sign_loss,
+                    clippy::cast_precision_loss
+                )]
+                let idx = (level * last as f64).round() as usize;
+                Self::BARS[idx.min(last)]
+            })
+            .collect()
+    }
+}
+
+/// Unit prefixes, and optionally a unit, to format a number with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    /// Decimal prefixes, such as "k" and "M"
+    Si,
+    /// Binary prefixes, such as "Ki" and "Mi"
+    Iec,
+    /// Bytes, with binary prefixes, such as "KiB"
+    Bytes,
+    /// Bits, with decimal prefixes, such as "kb"
+    Bits,
+    /// Hertz, with decimal prefixes, such as "kHz"
+    Hz,
+}
+
+impl Scale {
+    const SI: [&'static str; 7] = ["", "k", "M", "G", "T", "P", "E"];
+    const IEC: [&'static str; 7] = ["", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+
+    /// Returns `value` divided by the largest prefix it's at least one of,
+    /// with `precision` digits after the decimal point, followed by the
+    /// prefix and unit. Values too small for any prefix are left as is.
+    pub fn format(self, value: f64, precision: usize) -> String {
+        let (base, prefixes, unit) = match self {
+            Self::Si => (1000.0, Self::SI, ""),
+            Self::Iec => (1024.0, Self::IEC, ""),
+            Self::Bytes => (1024.0, Self::IEC, "B"),
+            Self::Bits => (1000.0, Self::SI, "b"),
+            Self::Hz => (1000.0, Self::SI, "Hz"),
+        };
+
+        let mut scaled = value;
+        let mut idx = 0;
+        while scaled.abs() >= base && idx < prefixes.len() - 1 {
+            scaled /= base;
+            idx += 1;
+        }
+
+        let number = if idx == 0 {
+            value.to_string()
+        } else {
+            format!("{scaled:.precision$}")
+        };
+        let suffix = format!("{}{unit}", prefixes[idx]);
+        if suffix.is_empty() {
+            number
+        } else {
+            format!("{number} {suffix}")
+        }
+    }
+}
+
+/// Configuration for padding a number to a fixed width.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Pad {
+    /// Minimum number of characters in the padded number
+    pub width: usize,
+    /// Alignment of the number within `width`
+    #[serde(default = "Pad::default_align")]
+    pub align: Align,
+    /// Character to pad the number with
+    #[serde(default = "Pad::default_fill")]
+    pub fill: char,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+impl Pad {
+    const fn default_align() -> Align {
+        Align::Right
+    }
+
+    const fn default_fill() -> char {
+        ' '
+    }
+
+    /// Returns `number`, padded with `fill` to at least `width` characters.
+    pub fn pad(&self, number: &str) -> String {
+        let missing = self.width.saturating_sub(number.chars().count());
+        let (before, after) = match self.align {
+            Align::Left => (0, missing),
+            Align::Right => (missing, 0),
+            Align::Center => (missing / 2, missing - missing / 2),
+        };
+        let fill = |count| core::iter::repeat(self.fill).take(count);
+        fill(before)
+            .chain(number.chars())
+            .chain(fill(after))
+            .collect()
+    }
+}
+
+/// Configuration for marking whether a number rose or fell since the
+/// previous one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Trend {
+    /// Marker of a number which rose
+    #[serde(default = "Trend::default_up")]
+    pub up: String,
+    /// Marker of a number which fell
+    #[serde(default = "Trend::default_down")]
+    pub down: String,
+    /// Marker of a number which didn't change by more than `deadband`
+    #[serde(default = "Trend::default_flat")]
+    pub flat: String,
+    /// Largest change which is still considered flat
+    #[serde(default)]
+    pub deadband: f64,
+    /// Whether the marker goes before or after `full_text`
+    #[serde(default)]
+    pub position: TrendPosition,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+impl Trend {
+    fn default_up() -> String {
+        "↑".to_owned()
+    }
+
+    fn default_down() -> String {
+        "↓".to_owned()
+    }
+
+    fn default_flat() -> String {
+        "→".to_owned()
+    }
+
+    /// Returns the marker of `value` compared to the `previous` one.
+    pub fn marker(&self, previous: f64, value: f64) -> &str {
+        let change = value - previous;
+        if change > self.deadband {
+            &self.up
+        } else if change < -self.deadband {
+            &self.down
+        } else {
+            &self.flat
+        }
+    }
+}
+
+/// Where a [`Trend`] marker goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrendPosition {
+    /// Before `full_text`
+    Before,
+    /// After `full_text`
+    #[default]
+    After,
+}
+
+/// Command run outside of any block, on startup or shutdown.
+#[derive(Clone, Debug)]
+pub struct Hook {
+    /// Configuration key the command was defined by
+    name: &'static str,
+    program: String,
+    /// Program and arguments of the shell to pass `program` to, if any
+    shell: Option<Vec<String>>,
+    command_dir: PathBuf,
+    env: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl Hook {
+    /// Run the command, and wait for it to exit or time out. Whether it
+    /// succeeded is logged.
+    ///
+    /// # Errors
+    ///
+    /// The command may fail to run, exit with failure, or time out.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let span = span!(
+            Level::INFO,
+            "hook_run",
+            name = self.name,
+            program = self.program
+        );
+
+        let result = self.try_run().await;
+        let _enter = span.enter();
+        match result {
+            Ok(()) => tracing::debug!("command succeeded"),
+            Err(ref err) => tracing::warn!(err = format_args!("{err:#}"), "command failed"),
+        }
+        result
+    }
+
+    async fn try_run(&self) -> anyhow::Result<()> {
+        let Self {
+            name, ref program, ..
+        } = *self;
+        let mut command = match self.shell.as_deref() {
+            Some([shell, args @ ..]) => {
+                let mut command = Command::new(&*block::resolve_program(shell, &self.command_dir));
+                command.args(args).arg(program);
+                command
+            }
+            _ => Command::new(&*block::resolve_program(program, &self.command_dir)),
+        };
+        command
+            .kill_on_drop(true)
+            .current_dir(&self.command_dir)
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("failed to execute {name} `{program}`"))?;
+        let status = time::timeout(self.timeout, child.wait())
+            .await
+            .map_err(|_| anyhow!("{name} `{program}` timed out"))?
+            .with_context(|| format!("failed to wait for {name} `{program}`"))?;
+        if !status.success() {
+            return Err(anyhow!("{name} `{program}` exited with {status}"));
+        }
+        Ok(())
+    }
+}
+
+/// What a [`Config`] was read from, which reloading reads again.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// The file at the configuration's path, which is canonicalized when it's
+    /// read if `canonicalize` is true
+    File { canonicalize: bool },
+    /// A string, parsed as if it were a file at the configuration's path
+    Str(Arc<str>),
+    /// An already parsed configuration, given to [`Config::new`], which is
+    /// built again rather than read
+    Toml {
+        toml: Arc<TomlBar>,
+        command_dir: PathBuf,
+    },
 }
 
 /// Convenience struct for easy access to all configuration options.
 #[derive(Debug)]
 pub struct Config {
-    /// Path of the TOML configuration file
+    /// Path of the TOML configuration file, or for a string, the path it's
+    /// parsed as if it were a file at
     pub path: PathBuf,
+    /// What the configuration was read from
+    pub source: Source,
+    /// Name of the selected profile, if any
+    pub profile: Option<String>,
     /// Path to execute block commands in
     pub command_dir: PathBuf,
+    /// Path of the pidfile, if configured
+    pub pidfile: Option<PathBuf>,
+    /// Path of the control socket, if configured
+    pub control_socket: Option<PathBuf>,
+    /// Path of the command audit log, if enabled
+    pub audit_log: Option<PathBuf>,
+    /// Environment variables set for block commands, read from `env_file`
+    /// and then `env`
+    pub env: Vec<(String, String)>,
     /// Bar's direct TOML configuration
     pub toml: TomlBar,
 }
 
 impl Config {
-    /// Read a TOML configuration from the given `path`, and return it
-    /// as a [`Config`].
+    /// Name of the directory, next to the configuration file, containing
+    /// drop-in files.
+    pub const DROP_IN_DIR: &'static str = "blocks.d";
+
+    /// Build a [`Config`] from an already parsed `toml`, as if it were read
+    /// from `path`, completing and validating it like
+    /// [`Config::read_from_path`] does. Commands are executed in
+    /// `command_dir`, which is used as given, and which relative paths in the
+    /// configuration, like `pidfile`, `include`, and `env_file`, are relative
+    /// to.
+    ///
+    /// Only files the configuration names, like those of `include` and
+    /// `env_file`, are read: drop-in files aren't. Reloading builds the
+    /// configuration from `toml` again, rather than reading `path` (see
+    /// [`Source::Toml`]).
     ///
     /// # Errors
     ///
-    /// - Canonicalizing `path` may fail
-    /// - Reading from `path` may fail
-    /// - `path` contents may contain invalid UTF-8
-    /// - `path` contents may be invalid TOML
-    #[tracing::instrument]
-    pub fn read_from_path(path: &Path) -> anyhow::Result<Self> {
-        /* canonicalize path before doing anything else. this is important for
-         * getting `command_dir` bc its `path`'s parent */
-        let path = path
-            .canonicalize()
-            .context("failed to canonicalize config path")?;
-
-        let mut toml: TomlBar = {
-            // TODO: would be nice to parse toml from `impl Read`
-            let mut file = OpenOptions::new()
-                .read(true)
-                .open(&path)
-                .context("failed to open config file")?;
-            let file_size = file
-                .metadata()
-                .ok()
-                .map(|metadata| metadata.len())
-                .and_then(|len| usize::try_from(len).ok())
-                .unwrap_or(0);
-            let mut bytes = Vec::new();
-            bytes
-                .try_reserve(file_size)
-                .context("failed to allocate memory for config file")?;
-            file.read_to_end(&mut bytes)
-                .context("failed to read config file")?;
-            let utf8 = str::from_utf8(&bytes).context("invalid utf-8")?;
-            toml::from_str(utf8)?
+    /// - Reading or parsing included files or `env_file` may fail
+    /// - Paths may reference unset environment variables
+    /// - Colors of `color_when` or `separator_color` may be invalid
+    /// - `timeout`, `kill_grace`, or `sparkline` may be invalid
+    /// - `idle_dim` or `block_shutdown_timeout` may be invalid
+    /// - `smolbar_version` may be unsatisfied
+    pub fn new(toml: TomlBar, path: PathBuf, command_dir: PathBuf) -> anyhow::Result<Self> {
+        let source = Source::Toml {
+            toml: Arc::new(toml.clone()),
+            command_dir: command_dir.clone(),
         };
+        let base_dir = command_dir.clone();
+        Self::build(toml, path, &base_dir, Some(command_dir), source)
+    }
+
+    /// Complete and validate `toml`, which was read from `source` at `path`.
+    ///
+    /// Relative paths in the configuration are relative to `base_dir`.
+    /// Commands are executed in `command_dir` if given. Otherwise, like a
+    /// file (see [`Config::from_str`]), blocks are read from drop-in files
+    /// too, and commands are executed in `base_dir`, or in the configured
+    /// `command_dir` relative to it, canonicalized.
+    fn build(
+        mut toml: TomlBar,
+        path: PathBuf,
+        base_dir: &Path,
+        command_dir: Option<PathBuf>,
+        source: Source,
+    ) -> anyhow::Result<Self> {
+        /* blocks in included files are appended to those in the main
+         * config, followed by those in drop-in files */
+        {
+            let mut including = vec![path.canonicalize().unwrap_or_else(|_| path.clone())];
+            let included = read_includes(base_dir, &toml.include, &mut including)
+                .context("failed to load included files")?;
+            toml.blocks.extend(included);
+        }
+        if command_dir.is_none() {
+            let drop_in_dir = base_dir.join(Self::DROP_IN_DIR);
+            let drop_ins = read_drop_ins(&drop_in_dir).context("failed to load drop-in files")?;
+            toml.blocks.extend(drop_ins);
+        }
+
+        toml.expand_env()?;
+
+        tracing::trace!(
+            num = toml.blocks.len(),
+            path = format_args!(r#""{}""#, path.display()),
+            "read block(s)",
+        );
 
         /* check version, just in case */
         if toml.header.version != Header::DEFAULT_VERSION {
@@ -128,16 +1399,47 @@ impl Config {
             );
         }
 
+        toml.warn_unknown();
+        toml.validate()?;
+
+        /* disabled blocks are still validated, so that enabling one doesn't
+         * reveal mistakes in it */
+        let num_blocks = toml.blocks.len();
+        toml.blocks.retain(|block| block.enabled);
+        if toml.blocks.len() < num_blocks {
+            tracing::debug!(
+                num = num_blocks - toml.blocks.len(),
+                "left out disabled block(s)"
+            );
+        }
+
+        toml.warn_signal_clashes();
+
         /* HACK: if full_text is not defined, we still want prefix and postfix
          * to apply to it (it being "") */
         if toml.body.full_text.is_none() {
             toml.body.full_text = Some(CowStr::new());
         }
 
-        /* check smolbar version */
-        {
+        /* border widths default to 1 only along with a border color, so that
+         * no border is drawn in an unspecified color. widths set in either
+         * scope are left alone. */
+        default_border_widths(&mut toml.body, None);
+        for block in &mut toml.blocks {
+            default_border_widths(&mut block.body, Some(&toml.body));
+        }
+
+        /* default_markup is applied to blocks at local scope, unless they
+         * already define it */
+        if let Some(markup) = toml.default_markup {
+            for block in &mut toml.blocks {
+                block.body.markup.get_or_insert(markup);
+            }
+        }
+
+        /* check smolbar version, unless the configuration opted out */
+        if let Some(required) = toml.smolbar_version.requirement() {
             let current = TomlBar::current_smolbar_version();
-            let required = &toml.smolbar_version;
 
             let span = span!(
                 Level::INFO,
@@ -156,11 +1458,77 @@ impl Config {
                 ))
                 .context("this configuration is unsupported by the current version of smolbar")?;
             }
+        } else {
+            tracing::debug!("smolbar_version is false, not checking version");
         }
 
+        let command_dir = match command_dir {
+            Some(command_dir) => command_dir,
+            None => Self::resolve_command_dir(&toml, base_dir)?,
+        };
+        tracing::info!(
+            path = format_args!(r#""{}""#, command_dir.display()),
+            "set command_dir"
+        );
+
+        let env = Self::take_env(&mut toml, base_dir)?;
+
+        /* like command_dir, a relative pidfile, control socket, or audit log
+         * is relative to the config path parent */
+        let pidfile = toml.pidfile.as_ref().map(|pidfile| base_dir.join(pidfile));
+        let control_socket = toml
+            .control_socket
+            .as_ref()
+            .map(|control_socket| base_dir.join(control_socket));
+        let audit_log = toml
+            .audit_log
+            .as_ref()
+            .map(|audit_log| base_dir.join(audit_log));
+
+        let config = Self {
+            path,
+            source,
+            profile: None,
+            command_dir,
+            pidfile,
+            control_socket,
+            audit_log,
+            env,
+            toml,
+        };
+        config.check_prlimit();
+        Ok(config)
+    }
+
+    /// Returns the environment variables set for block commands, read from
+    /// `env_file` relative to `base_dir`, and then taken from `env`.
+    fn take_env(toml: &mut TomlBar, base_dir: &Path) -> anyhow::Result<Vec<(String, String)>> {
+        /* env_file is reread on every load, so that reloading picks up any
+         * changes to it. like command_dir, a relative path is relative to the
+         * config path parent. */
+        let mut env = if let Some(ref env_file) = toml.env_file {
+            let env_path = base_dir.join(env_file);
+            let env = read_env_file(&env_path).context("failed to load env_file")?;
+            tracing::info!(
+                path = format_args!(r#""{}""#, env_path.display()),
+                num = env.len(),
+                "read env_file"
+            );
+            env
+        } else {
+            Vec::new()
+        };
+        /* set after those of env_file, so they take precedence */
+        env.extend(mem::take(&mut toml.env));
+        Ok(env)
+    }
+
+    /// Returns the directory to execute commands in: `base_dir`, or the
+    /// configured `command_dir` relative to it, canonicalized.
+    fn resolve_command_dir(toml: &TomlBar, base_dir: &Path) -> anyhow::Result<PathBuf> {
         /* command_dir is either the config's parent path or whatever is
          * specified in toml */
-        let mut command_dir = path.parent().unwrap_or(&path).to_path_buf();
+        let mut command_dir = base_dir.to_path_buf();
         if let Some(ref dir) = toml.command_dir {
             /* if the toml command_dir is relative, its appended to the config
              * path parent. otherwise, it replaces it. */
@@ -173,25 +1541,933 @@ impl Config {
             path = format_args!(r#""{}""#, command_dir.display()),
             "canonicalizing command_dir",
         );
-        command_dir = command_dir
+        command_dir
             .canonicalize()
-            .context("failed to canonicalize command_dir")?;
+            .context("failed to canonicalize command_dir")
+    }
+
+    /// Read a TOML configuration from the given `path`, and return it
+    /// as a [`Config`]. It's parsed like [`Config::from_str`], as a file in
+    /// the directory containing `path`.
+    ///
+    /// If `canonicalize` is false, `path` is used as given (only made
+    /// absolute), so symlinks are resolved again each time it's read, and
+    /// `command_dir` is relative to the directory containing the symlink
+    /// rather than its target.
+    ///
+    /// If `profile` is given, the profile of that name is merged over the
+    /// rest of the configuration (see [`merge_profile`]).
+    ///
+    /// # Errors
+    ///
+    /// - Canonicalizing `path`, or getting the current directory, may fail
+    /// - Reading from `path` may fail
+    /// - `path` contents may contain invalid UTF-8
+    /// - Parsing the configuration may fail (see [`Config::from_str`])
+    #[tracing::instrument]
+    pub fn read_from_path(
+        path: &Path,
+        canonicalize: bool,
+        profile: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        /* canonicalize path before doing anything else. this is important for
+         * getting `command_dir` bc its `path`'s parent */
+        let path = if canonicalize {
+            path.canonicalize()
+                .context("failed to canonicalize config path")?
+        } else {
+            /* even if not canonical, the path must be absolute for its parent
+             * to be meaningful */
+            env::current_dir()
+                .context("failed to get current directory")?
+                .join(path)
+        };
+
+        let utf8 = read_to_string(&path).context("failed to read config file")?;
+        let mut config = Self::from_str_at(&utf8, path, profile)?;
+        config.source = Source::File { canonicalize };
+        Ok(config)
+    }
+
+    /// Read the configuration at `path` again from `source`, which it was
+    /// first read from, selecting `profile` if given.
+    ///
+    /// # Errors
+    ///
+    /// Reading or parsing the configuration may fail (see
+    /// [`Config::read_from_path`] and [`Config::from_str`]).
+    pub fn reread(path: &Path, source: &Source, profile: Option<&str>) -> anyhow::Result<Self> {
+        match source {
+            Source::File { canonicalize } => Self::read_from_path(path, *canonicalize, profile),
+            Source::Str(utf8) => Self::from_str_at(utf8, path.to_path_buf(), profile),
+            /* profiles were already selected, if any, before it was parsed */
+            Source::Toml { toml, command_dir } => {
+                Self::new((**toml).clone(), path.to_path_buf(), command_dir.clone())
+            }
+        }
+    }
+
+    /// Parse the TOML configuration `utf8`, as if it were a file in
+    /// `base_dir`, and return it as a [`Config`].
+    ///
+    /// Relative paths in the configuration, like `command_dir`, `include`,
+    /// and `env_file`, are relative to `base_dir`, and `command_dir` is
+    /// canonicalized against it. Blocks are read from drop-in files in
+    /// `base_dir` too. Reloading parses `utf8` again (see
+    /// [`Config::reread`]).
+    ///
+    /// # Errors
+    ///
+    /// - `utf8` may be invalid TOML
+    /// - Canonicalizing `command_dir` may fail
+    /// - Reading or parsing `env_file` may fail
+    /// - Reading or parsing included or drop-in files may fail
+    /// - Validating the configuration may fail (see [`Config::new`])
+    pub fn from_str(utf8: &str, base_dir: &Path) -> anyhow::Result<Self> {
+        Self::from_str_at(utf8, base_dir.join("<string>"), None)
+    }
+
+    /// Parse the TOML configuration `utf8` like [`Config::from_str`], as if it
+    /// were the file at `path`, selecting `profile` if given.
+    fn from_str_at(utf8: &str, path: PathBuf, profile: Option<&str>) -> anyhow::Result<Self> {
+        let toml: TomlBar = {
+            let mut table: toml::Table = toml::from_str(utf8)?;
+            if let Some(profile) = profile {
+                /* parsing straight to TomlBar reports errors more precisely, so
+                 * this is only done if needed */
+                merge_profile(&mut table, profile)?;
+                tracing::info!(profile, "selected profile");
+                resolve_templates(&mut table)?;
+                toml::Value::Table(table)
+                    .try_into()
+                    .with_context(|| format!(r#"invalid config with profile "{profile}""#))?
+            } else if uses_templates(&table) {
+                resolve_templates(&mut table)?;
+                toml::Value::Table(table)
+                    .try_into()
+                    .context("invalid config with templates")?
+            } else {
+                toml::from_str(utf8)?
+            }
+        };
+
+        let base_dir = path.parent().unwrap_or(&path).to_path_buf();
+        let source = Source::Str(Arc::from(utf8));
+        let mut config = Self::build(toml, path, &base_dir, None, source)?;
+        config.profile = profile.map(str::to_string);
+        Ok(config)
+    }
+
+    /// Warn if `prlimit(1)` can't be executed but any block sets resource
+    /// limits, since they're enforced by executing its command through it.
+    /// Such blocks still run their commands, without the limits.
+    fn check_prlimit(&self) {
+        if let Some(id) = self
+            .toml
+            .blocks
+            .iter()
+            .position(|block| block.rlimit_cpu.is_some() || block.rlimit_mem.is_some())
+        {
+            if let Err(err) = self.find_program("prlimit", &self.command_dir) {
+                tracing::warn!(
+                    id,
+                    err = format_args!("{err:#}"),
+                    "block sets rlimit_cpu or rlimit_mem, but prlimit(1), from util-linux, is unusable, so commands run without resource limits"
+                );
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Check that the command of every block, and the shell it's executed
+    /// through, can be found and is executable, without running any of them.
+    /// Through a shell, a command's program is only checked if it's a path.
+    ///
+    /// Returns every problem found, naming the block it belongs to.
+    pub fn check_commands(&self) -> Vec<anyhow::Error> {
+        let mut problems = Vec::new();
+        if let Some(&shell) = self
+            .toml
+            .shell
+            .argv()
+            .as_ref()
+            .and_then(|argv| argv.first())
+        {
+            if let Err(err) = self.find_program(shell, &self.command_dir) {
+                problems.push(err.context(format!("shell `{shell}` is unusable")));
+            }
+        }
+        for hook in [self.pre_command(), self.post_command()]
+            .into_iter()
+            .flatten()
+        {
+            let Some(program) = self.toml.shell.program(&hook.program) else {
+                continue;
+            };
+            if let Err(err) = self.find_program(program, &self.command_dir) {
+                problems.push(err.context(format!(
+                    "{name} `{program}` is unusable",
+                    name = hook.name,
+                    program = hook.program
+                )));
+            }
+        }
+        for (id, block) in self.toml.blocks.iter().enumerate() {
+            let command_dir = block.command_dir.as_deref().unwrap_or(&self.command_dir);
+            let name = block
+                .body
+                .name
+                .as_ref()
+                .map_or_else(String::new, |name| format!(r#" ("{name}")"#));
+            let shell = block.shell.as_ref().unwrap_or(&self.toml.shell);
+            if let Some(ref block_shell) = block.shell {
+                if let Some(&program) = block_shell.argv().as_ref().and_then(|argv| argv.first()) {
+                    if let Err(err) = self.find_program(program, command_dir) {
+                        problems.push(
+                            err.context(format!("block {id}{name} has unusable shell `{program}`")),
+                        );
+                    }
+                }
+            }
+            for (field, command) in [("command", &block.command), ("on_click", &block.on_click)] {
+                let Some(program) = command
+                    .as_deref()
+                    .and_then(|command| shell.program(command))
+                else {
+                    continue;
+                };
+                if let Err(err) = self.find_program(program, command_dir) {
+                    problems.push(
+                        err.context(format!("block {id}{name} has unusable {field} `{program}`")),
+                    );
+                }
+            }
+        }
+        problems
+    }
+
+    /// Returns the configured `pre_command`, if any.
+    pub fn pre_command(&self) -> Option<Hook> {
+        let program = self.toml.pre_command.clone()?;
+        Some(self.hook("pre_command", program, self.toml.pre_command_timeout))
+    }
+
+    /// Returns the configured `post_command`, if any.
+    pub fn post_command(&self) -> Option<Hook> {
+        let program = self.toml.post_command.clone()?;
+        Some(self.hook("post_command", program, self.toml.post_command_timeout))
+    }
+
+    fn hook(&self, name: &'static str, program: String, timeout: f32) -> Hook {
+        Hook {
+            name,
+            program,
+            shell: self
+                .toml
+                .shell
+                .argv()
+                .map(|argv| argv.into_iter().map(str::to_owned).collect()),
+            command_dir: self.command_dir.clone(),
+            env: self.env.clone(),
+            /* this was validated when reading the config */
+            timeout: Duration::try_from_secs_f32(timeout).unwrap_or_default(),
+        }
+    }
+
+    /// Run `pre_command`, if configured, and wait for it to exit or time out.
+    ///
+    /// # Errors
+    ///
+    /// If `pre_command` is required, returns why it failed. Otherwise, its
+    /// failure is only logged.
+    pub async fn run_pre_command(&self) -> anyhow::Result<()> {
+        let Some(hook) = self.pre_command() else {
+            return Ok(());
+        };
+        match hook.run().await {
+            Err(err) if self.toml.pre_command_required => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Find the path that `program` would be executed from, mirroring how
+    /// block commands are spawned.
+    fn find_program(&self, program: &str, command_dir: &Path) -> anyhow::Result<PathBuf> {
+        let program = block::resolve_program(program, command_dir);
+        if program
+            .to_str()
+            .map_or(true, |program| program.contains('/'))
+        {
+            /* paths are relative to the working directory of the command */
+            let path = command_dir.join(program);
+            check_executable(&path)?;
+            Ok(path)
+        } else {
+            /* env_file may have overridden $PATH */
+            let path_var = self
+                .env
+                .iter()
+                .rev()
+                .find(|(key, _value)| key == "PATH")
+                .map(|(_key, value)| OsString::from(value))
+                .or_else(|| env::var_os("PATH"))
+                .ok_or_else(|| anyhow!("$PATH is not set"))?;
+            env::split_paths(&path_var)
+                .map(|dir| command_dir.join(dir).join(&program))
+                .find(|path| check_executable(path).is_ok())
+                .ok_or_else(|| anyhow!("not found in $PATH"))
+        }
+    }
+}
+
+/// Set each unset border width of `body` to 1 if it has a `border` color,
+/// unless `global` sets that width.
+fn default_border_widths(body: &mut Body, global: Option<&Body>) {
+    if body.border.is_none() {
+        return;
+    }
+    for (width, global_width) in [
+        (
+            &mut body.border_top,
+            global.and_then(|global| global.border_top),
+        ),
+        (
+            &mut body.border_bottom,
+            global.and_then(|global| global.border_bottom),
+        ),
+        (
+            &mut body.border_left,
+            global.and_then(|global| global.border_left),
+        ),
+        (
+            &mut body.border_right,
+            global.and_then(|global| global.border_right),
+        ),
+    ] {
+        if width.is_none() && global_width.is_none() {
+            *width = Some(Body::DEFAULT_BORDER_WIDTH);
+        }
+    }
+}
+
+/// Check that `path` is an executable file.
+fn check_executable(path: &Path) -> anyhow::Result<()> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!(r#"failed to access "{}""#, path.display()))?;
+    if !metadata.is_file() {
+        return Err(anyhow!(r#""{}" is not a file"#, path.display()));
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow!(r#""{}" is not executable"#, path.display()));
+    }
+    Ok(())
+}
+
+/// Drop-in or included file configuration, directly deserialized.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct TomlDropIn {
+    /// Paths of files whose blocks are appended to those of this file
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default = "Vec::new", rename = "block")]
+    blocks: Vec<TomlBlock>,
+    /// Fields which aren't known, like [`TomlBar::unknown`]
+    #[serde(flatten, skip_serializing)]
+    unknown: toml::Table,
+}
+
+/// Merge the profile named `profile` over the rest of `table`.
+///
+/// Tables in the profile are merged with those in the rest of the
+/// configuration, and any other value replaces the one it's merged with. In
+/// particular, if the profile defines any blocks, they replace all other
+/// blocks.
+fn merge_profile(table: &mut toml::Table, profile: &str) -> anyhow::Result<()> {
+    let mut profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(anyhow!("profiles must be a table")),
+        None => toml::Table::new(),
+    };
+    let overlay = match profiles.remove(profile) {
+        Some(toml::Value::Table(overlay)) => overlay,
+        Some(_) => return Err(anyhow!(r#"profile "{profile}" must be a table"#)),
+        None => {
+            return Err(anyhow!(
+                r#"profile "{profile}" is not defined (defined profiles: {defined})"#,
+                defined = list_keys(&profiles),
+            ));
+        }
+    };
+    merge(table, overlay);
+    Ok(())
+}
+
+/// Merge `overlay` over `base`. Tables are merged recursively, and any other
+/// value replaces the one it's merged with.
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge(base, overlay);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Returns the comma-separated keys of `table`, or "none" if it's empty.
+fn list_keys(table: &toml::Table) -> String {
+    if table.is_empty() {
+        "none".to_string()
+    } else {
+        table
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Returns whether `table` defines templates or any block uses one.
+fn uses_templates(table: &toml::Table) -> bool {
+    table.contains_key("templates")
+        || matches!(table.get("block"), Some(toml::Value::Array(blocks))
+            if blocks.iter().any(|block| block.get("use").is_some()))
+}
+
+/// Merge the template each block in `table` uses under the block's own
+/// fields, so the block overrides any of them.
+///
+/// Templates are tables in `templates`, which may themselves use another
+/// template. Using an undefined template, or templates which use each other
+/// in a cycle, is an error.
+fn resolve_templates(table: &mut toml::Table) -> anyhow::Result<()> {
+    let templates = match table.remove("templates") {
+        Some(toml::Value::Table(templates)) => templates,
+        Some(_) => return Err(anyhow!("templates must be a table")),
+        None => toml::Table::new(),
+    };
+    /* templates are checked even if no block uses them */
+    for name in templates.keys() {
+        let mut table = toml::Table::new();
+        table.insert("use".to_string(), toml::Value::String(name.clone()));
+        with_template(&templates, table, &mut Vec::new())
+            .with_context(|| format!(r#"failed to resolve template "{name}""#))?;
+    }
+    if let Some(toml::Value::Array(blocks)) = table.get_mut("block") {
+        for (id, block) in blocks.iter_mut().enumerate() {
+            /* anything else is reported when parsing the block */
+            if let toml::Value::Table(block) = block {
+                *block = with_template(&templates, mem::take(block), &mut Vec::new())
+                    .with_context(|| format!("failed to resolve template of block {id}"))?;
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Returns `table` merged over the template it uses, if any. `using` is the
+/// chain of templates already being resolved, to detect cycles.
+fn with_template(
+    templates: &toml::Table,
+    mut table: toml::Table,
+    using: &mut Vec<String>,
+) -> anyhow::Result<toml::Table> {
+    let name = match table.remove("use") {
+        Some(toml::Value::String(name)) => name,
+        Some(_) => return Err(anyhow!("use must be a string")),
+        None => return Ok(table),
+    };
+    if using.contains(&name) {
+        using.push(name);
+        return Err(anyhow!(
+            "templates use each other in a cycle: {}",
+            using.join(" -> ")
+        ));
+    }
+    let template = match templates.get(&name) {
+        Some(toml::Value::Table(template)) => template.clone(),
+        Some(_) => return Err(anyhow!(r#"template "{name}" must be a table"#)),
+        None => {
+            return Err(anyhow!(
+                r#"template "{name}" is not defined (defined templates: {defined})"#,
+                defined = list_keys(templates),
+            ));
+        }
+    };
+    using.push(name);
+    let mut base = with_template(templates, template, using)?;
+    merge(&mut base, table);
+    Ok(base)
+}
+
+/// Read the blocks of every `*.toml` file in `dir`, sorted by file name.
+///
+/// The blocks execute their commands in `dir`. If `dir` doesn't exist, there
+/// are no blocks.
+fn read_drop_ins(dir: &Path) -> anyhow::Result<Vec<TomlBlock>> {
+    let span = span!(
+        Level::INFO,
+        "config_read_drop_ins",
+        dir = format_args!(r#""{}""#, dir.display())
+    );
+    let _enter = span.enter();
+
+    if !dir.is_dir() {
+        tracing::trace!("no drop-in directory");
+        return Ok(Vec::new());
+    }
+    let dir = dir
+        .canonicalize()
+        .context("failed to canonicalize drop-in directory")?;
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&dir).context("failed to read drop-in directory")? {
+        let path = entry.context("failed to read drop-in directory")?.path();
+        if path.extension().map_or(false, |ext| ext == "toml") && path.is_file() {
+            paths.push(path);
+        } else {
+            tracing::trace!(
+                path = format_args!(r#""{}""#, path.display()),
+                "skipping non-toml file"
+            );
+        }
+    }
+    paths.sort();
+
+    let command_dir = Arc::new(dir);
+    let mut blocks = Vec::new();
+    for path in paths {
+        let drop_in: TomlDropIn = {
+            let utf8 = read_to_string(&path)
+                .with_context(|| format!(r#"failed to read "{}""#, path.display()))?;
+            toml::from_str(&utf8)
+                .with_context(|| format!(r#"failed to parse "{}""#, path.display()))?
+        };
         tracing::info!(
-            path = format_args!(r#""{}""#, command_dir.display()),
-            "set command_dir"
+            path = format_args!(r#""{}""#, path.display()),
+            num = drop_in.blocks.len(),
+            "read drop-in file"
+        );
+        for field in drop_in.unknown.keys() {
+            tracing::warn!(
+                path = format_args!(r#""{}""#, path.display()),
+                field,
+                "unknown field of drop-in file, ignoring it"
+            );
+        }
+        blocks.extend(drop_in.blocks.into_iter().map(|mut block| {
+            block.command_dir = Some(Arc::clone(&command_dir));
+            block
+        }));
+        blocks.extend(
+            read_includes(&command_dir, &drop_in.include, &mut vec![path.clone()]).with_context(
+                || format!(r#"failed to load files included by "{}""#, path.display()),
+            )?,
         );
+    }
+    Ok(blocks)
+}
 
-        tracing::trace!(
-            num = toml.blocks.len(),
-            path = format_args!(r#""{}""#, path.display()),
-            "read block(s)",
+/// Read the blocks of each file in `includes`, in order, relative to `dir`,
+/// followed by those of the files it includes in turn.
+///
+/// The blocks execute their commands in the directory of the file defining
+/// them. `including` holds the canonical paths of the files which included
+/// these, such that including one of them again is an error, rather than
+/// an endless cycle.
+fn read_includes(
+    dir: &Path,
+    includes: &[String],
+    including: &mut Vec<PathBuf>,
+) -> anyhow::Result<Vec<TomlBlock>> {
+    let mut blocks = Vec::new();
+    for include in includes {
+        let include = expand_env(include).context("failed to expand include")?;
+        let path = dir
+            .join(&include)
+            .canonicalize()
+            .with_context(|| format!(r#"failed to find included file "{include}""#))?;
+        if including.contains(&path) {
+            return Err(anyhow!(
+                r#"included file "{}" includes itself, directly or through others"#,
+                path.display()
+            ));
+        }
+
+        let span = span!(
+            Level::INFO,
+            "config_read_include",
+            path = format_args!(r#""{}""#, path.display())
         );
+        let _enter = span.enter();
 
-        Ok(Self {
-            path,
-            command_dir,
-            toml,
-        })
+        let file: TomlDropIn = {
+            let utf8 = read_to_string(&path)
+                .with_context(|| format!(r#"failed to read "{}""#, path.display()))?;
+            toml::from_str(&utf8)
+                .with_context(|| format!(r#"failed to parse "{}""#, path.display()))?
+        };
+        tracing::info!(num = file.blocks.len(), "read included file");
+        for field in file.unknown.keys() {
+            tracing::warn!(field, "unknown field of included file, ignoring it");
+        }
+
+        let parent = path.parent().unwrap_or(&path).to_path_buf();
+        let command_dir = Arc::new(parent.clone());
+        blocks.extend(file.blocks.into_iter().map(|mut block| {
+            block.command_dir = Some(Arc::clone(&command_dir));
+            block
+        }));
+
+        including.push(path);
+        let nested = read_includes(&parent, &file.include, including);
+        let path = including.pop().expect("included file was just pushed");
+        blocks.extend(nested.with_context(|| {
+            format!(r#"failed to load files included by "{}""#, path.display())
+        })?);
+    }
+    Ok(blocks)
+}
+
+/// Read the entire file at `path` as UTF-8.
+fn read_to_string(path: &Path) -> anyhow::Result<String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .context("failed to open file")?;
+    let file_size = file
+        .metadata()
+        .ok()
+        .map(|metadata| metadata.len())
+        .and_then(|len| usize::try_from(len).ok())
+        .unwrap_or(0);
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve(file_size)
+        .context("failed to allocate memory for file")?;
+    file.read_to_end(&mut bytes)
+        .context("failed to read file")?;
+    let utf8 = String::from_utf8(bytes).context("invalid utf-8")?;
+    Ok(utf8)
+}
+
+/// Expand references to environment variables in `s`, written as `$VAR` or
+/// `${VAR}`, or as `${VAR:-default}` to use `default` if `VAR` is unset or
+/// empty. `$$` is a literal `$`, as is a `$` which isn't followed by a name.
+///
+/// # Errors
+///
+/// A referenced variable may be unset (without a default), or not be valid
+/// unicode.
+fn expand_env(s: &str) -> anyhow::Result<String> {
+    fn lookup(name: &str, default: Option<&str>) -> anyhow::Result<String> {
+        match (env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => Ok(default.to_owned()),
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some(default)) => Ok(default.to_owned()),
+            (Err(env::VarError::NotPresent), None) => {
+                Err(anyhow!("environment variable ${name} is unset"))
+            }
+            (Err(err), None) => {
+                Err(anyhow!(err).context(format!("invalid environment variable ${name}")))
+            }
+        }
+    }
+
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow!(r#"unclosed "${{" in "{s}""#))?;
+            let (name, default) = match braced[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&braced[..end], None),
+            };
+            expanded.push_str(&lookup(name, default)?);
+            rest = &braced[end + 1..];
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&lookup(&rest[..len], None)?);
+                rest = &rest[len..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Read environment variables from a `.env`-style file at `path`.
+///
+/// Each line is either blank, a comment starting with `#`, or a `KEY=VALUE`
+/// pair, optionally preceded by `export`. Values may be wrapped in single or
+/// double quotes, which are removed.
+fn read_env_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let contents = read_to_string(path)?;
+    let mut env = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected `KEY=VALUE`", idx + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(anyhow!("line {}: key is empty", idx + 1));
+        }
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .into_iter()
+            .find_map(|(start, end)| value.strip_prefix(start)?.strip_suffix(end))
+            .unwrap_or(value);
+        env.push((key.to_string(), value.to_string()));
+    }
+    Ok(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    use crate::block::Block;
+
+    fn config(utf8: &str) -> Config {
+        let toml = toml::from_str(utf8).unwrap();
+        Config::new(toml, "<test>".into(), env::temp_dir()).unwrap()
+    }
+
+    /// Returns the body of the first block of `config` after its command
+    /// printed `immediate`.
+    fn body(config: &Config, immediate: &str) -> Body {
+        Block::body_from_output(immediate, &config.toml.body, &config.toml.blocks[0])
+    }
+
+    #[test]
+    fn no_border_widths_without_border() {
+        let body = body(&config("[[block]]\nfull_text = \"a\""), "");
+        assert_eq!(body.border, None);
+        for width in [
+            body.border_top,
+            body.border_bottom,
+            body.border_left,
+            body.border_right,
+        ] {
+            assert_eq!(width, None);
+        }
+    }
+
+    #[test]
+    fn border_widths_default_with_border() {
+        let body = body(&config("[[block]]\nborder = \"#ff0000\""), "");
+        for width in [
+            body.border_top,
+            body.border_bottom,
+            body.border_left,
+            body.border_right,
+        ] {
+            assert_eq!(width, Some(Body::DEFAULT_BORDER_WIDTH));
+        }
+    }
+
+    #[test]
+    fn border_widths_default_with_global_border() {
+        let config = config("border = \"#ff0000\"\nborder_top = 2\n[[block]]\n");
+        let body = body(&config, "");
+        assert_eq!(body.border.as_deref(), Some("#ff0000"));
+        assert_eq!(body.border_top, Some(2));
+        assert_eq!(body.border_bottom, Some(Body::DEFAULT_BORDER_WIDTH));
+    }
+
+    #[test]
+    fn explicit_border_widths_kept_without_border() {
+        let config = config("border_left = 2\n[[block]]\nborder_bottom = 0");
+        let body = body(&config, "");
+        assert_eq!(body.border, None);
+        assert_eq!(body.border_bottom, Some(0));
+        assert_eq!(body.border_left, Some(2));
+        assert_eq!(body.border_top, None);
+    }
+
+    /// Shell syntax, such as unset variables, positional parameters, and
+    /// `$$`, is left in commands for the shell to expand.
+    #[test]
+    fn commands_keep_shell_syntax() {
+        const COMMAND: &str = "echo $SMOLBAR_TEST_UNSET ${SMOLBAR_TEST_UNSET} $1 $$ $NF";
+        let config = Config::from_str(
+            &format!(
+                r"
+                pre_command = '{COMMAND}'
+                post_command = '{COMMAND}'
+                [[block]]
+                command = '{COMMAND}'
+                on_click = '{COMMAND}'
+                "
+            ),
+            &env::temp_dir(),
+        )
+        .unwrap();
+        assert_eq!(config.toml.pre_command.as_deref(), Some(COMMAND));
+        assert_eq!(config.toml.post_command.as_deref(), Some(COMMAND));
+        let block = &config.toml.blocks[0];
+        assert_eq!(block.command.as_deref(), Some(COMMAND));
+        assert_eq!(block.on_click.as_deref(), Some(COMMAND));
+    }
+
+    #[test]
+    fn paths_are_expanded() {
+        let config = Config::from_str(
+            r#"pidfile = "${SMOLBAR_TEST_UNSET:-/tmp}/$$smolbar.pid""#,
+            &env::temp_dir(),
+        )
+        .unwrap();
+        assert_eq!(config.pidfile, Some("/tmp/$smolbar.pid".into()));
+    }
+
+    #[test]
+    fn unset_variable_in_path_fails() {
+        assert!(Config::from_str(
+            r#"pidfile = "$SMOLBAR_TEST_UNSET/smolbar.pid""#,
+            &env::temp_dir()
+        )
+        .is_err());
+    }
+
+    /// Parsing a string is like reading a file in `base_dir`, so drop-in
+    /// files are read there, and `command_dir` is canonicalized against it.
+    #[test]
+    fn from_str_reads_like_file() {
+        let dir = env::temp_dir().join(format!("smolbar-test-from-str-{}", process::id()));
+        fs::create_dir_all(dir.join(Config::DROP_IN_DIR)).unwrap();
+        fs::write(
+            dir.join(Config::DROP_IN_DIR).join("drop-in.toml"),
+            "[[block]]\nfull_text = \"drop-in\"",
+        )
+        .unwrap();
+
+        let config = Config::from_str(
+            "command_dir = \"blocks.d/..\"\n[[block]]\nfull_text = \"a\"",
+            &dir,
+        )
+        .unwrap();
+        assert_eq!(config.command_dir, dir.canonicalize().unwrap());
+        let full_texts: Vec<_> = config
+            .toml
+            .blocks
+            .iter()
+            .map(|block| block.body.full_text.as_deref())
+            .collect();
+        assert_eq!(full_texts, [Some("a"), Some("drop-in")]);
+        assert!(Config::from_str("command_dir = \"missing\"", &dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reread_parses_string_again() {
+        let config = Config::from_str("[[block]]\nfull_text = \"a\"", &env::temp_dir()).unwrap();
+        let reread = Config::reread(&config.path, &config.source, None).unwrap();
+        assert_eq!(reread.path, config.path);
+        assert_eq!(reread.toml.blocks[0].body.full_text.as_deref(), Some("a"));
+    }
+
+    /// A configuration built from parsed TOML is completed like one read from
+    /// a file, with relative paths relative to its `command_dir`.
+    #[test]
+    fn new_completes_config() {
+        let config = config(
+            r#"
+            pidfile = "${SMOLBAR_TEST_UNSET:-smolbar}.pid"
+            env = { SMOLBAR_TEST = "a" }
+            "#,
+        );
+        assert_eq!(config.pidfile, Some(env::temp_dir().join("smolbar.pid")));
+        assert_eq!(config.env, [("SMOLBAR_TEST".to_owned(), "a".to_owned())]);
+    }
+
+    /// Reloading builds a configuration from parsed TOML again, rather than
+    /// reading its path.
+    #[test]
+    fn reread_builds_toml_again() {
+        let config = config("[[block]]\nfull_text = \"a\"");
+        let reread = Config::reread(&config.path, &config.source, None).unwrap();
+        assert_eq!(reread.command_dir, config.command_dir);
+        assert_eq!(reread.toml.blocks[0].body.full_text.as_deref(), Some("a"));
+    }
+
+    /// Unknown fields of a block's tables are kept to be warned about, rather
+    /// than rejected.
+    #[test]
+    fn unknown_fields_of_tables_are_ignored() {
+        let config = config(
+            r"
+            idle_dim = { timeout = 1, opacity = 0.5, opactiy = 0.2 }
+            [[block]]
+            pad = { widht = 4, width = 2 }
+            urgent_when = { abvoe = 1 }
+            ",
+        );
+        assert!(config
+            .toml
+            .idle_dim
+            .unwrap()
+            .unknown
+            .contains_key("opactiy"));
+        let block = &config.toml.blocks[0];
+        assert_eq!(block.pad.as_ref().map(|pad| pad.width), Some(2));
+        let unknown: Vec<_> = block
+            .unknown_tables()
+            .into_iter()
+            .flat_map(|(table, unknown)| unknown.keys().map(move |field| (table, field.as_str())))
+            .collect();
+        assert_eq!(unknown, [("urgent_when", "abvoe"), ("pad", "widht")]);
+    }
+
+    #[test]
+    fn unknown_fields_of_drop_ins_are_ignored() {
+        let dir = env::temp_dir().join(format!("smolbar-test-drop-in-unknown-{}", process::id()));
+        fs::create_dir_all(dir.join(Config::DROP_IN_DIR)).unwrap();
+        fs::write(
+            dir.join(Config::DROP_IN_DIR).join("drop-in.toml"),
+            "iclude = []\ninclude = [\"../included.toml\"]\n[[block]]\nfull_text = \"drop-in\"",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("included.toml"),
+            "blocks = []\n[[block]]\nfull_text = \"included\"",
+        )
+        .unwrap();
+        fs::write(dir.join("config.toml"), "").unwrap();
+
+        let config = Config::read_from_path(&dir.join("config.toml"), true, None).unwrap();
+        let full_texts: Vec<_> = config
+            .toml
+            .blocks
+            .iter()
+            .map(|block| block.body.full_text.as_deref())
+            .collect();
+        assert_eq!(full_texts, [Some("drop-in"), Some("included")]);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
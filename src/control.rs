@@ -0,0 +1,170 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use anyhow::{anyhow, Context};
+use tracing::{span, Level};
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use crate::bar::{BarHandle, BarMsg};
+
+/// Command sent through the control socket, one per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Reload the configuration of the block with the given name
+    Reload(String),
+}
+
+impl ControlCommand {
+    /// Parse a line received through the control socket.
+    ///
+    /// # Errors
+    ///
+    /// The line may not be a known command.
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        match line.trim().split_once(' ') {
+            Some(("reload", name)) if !name.trim().is_empty() => {
+                Ok(Self::Reload(name.trim().to_owned()))
+            }
+            _ => Err(anyhow!(r#"unknown control command "{line}""#)),
+        }
+    }
+
+    /// Returns the line sent through the control socket for this command.
+    pub fn to_line(&self) -> String {
+        match self {
+            Self::Reload(name) => format!("reload {name}\n"),
+        }
+    }
+
+    fn into_msg(self) -> BarMsg {
+        match self {
+            Self::Reload(name) => BarMsg::ReloadBlock(name),
+        }
+    }
+}
+
+/// Listen on the control socket at `path` on a separate thread, sending each
+/// command received to `bar`. Like click events, commands are read on a
+/// thread rather than a task.
+///
+/// A socket left behind by a smolbar which is no longer running is
+/// replaced.
+///
+/// # Errors
+///
+/// - Another smolbar may be listening on `path`
+/// - Removing a stale socket, or binding to `path`, may fail
+/// - Spawning the thread may fail
+pub fn listen(path: &Path, bar: BarHandle) -> anyhow::Result<()> {
+    let listener = bind(path)?;
+    spawn_listener(listener, bar).context("failed to start reading control commands")
+}
+
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "another smolbar is listening on the control socket"
+            ));
+        }
+        tracing::debug!(
+            path = format_args!(r#""{}""#, path.display()),
+            "removing stale control socket"
+        );
+        fs::remove_file(path).context("failed to remove stale control socket")?;
+    }
+    UnixListener::bind(path).context("failed to bind control socket")
+}
+
+fn spawn_listener(listener: UnixListener, bar: BarHandle) -> io::Result<()> {
+    thread::Builder::new()
+        .name("control_listener".to_owned())
+        .spawn(move || {
+            let span = span!(Level::INFO, "control_listener");
+            let _enter = span.enter();
+
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        tracing::warn!("failed to accept control connection: {err}");
+                        continue;
+                    }
+                };
+                for line in BufReader::new(stream).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(err) => {
+                            tracing::warn!("failed to read control command: {err}");
+                            break;
+                        }
+                    };
+                    match ControlCommand::parse(&line) {
+                        Ok(command) => {
+                            tracing::trace!(?command, "received control command");
+                            if bar.blocking_send(command.into_msg()).is_err() {
+                                /* the bar shut down */
+                                return;
+                            }
+                        }
+                        Err(err) => tracing::warn!("{err}"),
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Send `command` to the smolbar listening on the control socket at `path`.
+///
+/// # Errors
+///
+/// Connecting to or writing to the socket may fail.
+pub fn send(path: &Path, command: &ControlCommand) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            r#"failed to connect to control socket "{}""#,
+            path.display()
+        )
+    })?;
+    stream
+        .write_all(command.to_line().as_bytes())
+        .context("failed to send control command")
+}
+
+/// Remove the control socket at `path`.
+pub fn remove(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        tracing::warn!(
+            err = format_args!("{err}"),
+            "failed to remove control socket"
+        );
+    } else {
+        tracing::trace!("removed control socket");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_round_trips() {
+        let command = ControlCommand::Reload("clock".to_owned());
+        assert_eq!(ControlCommand::parse(&command.to_line()).unwrap(), command);
+        assert_eq!(ControlCommand::parse("  reload  clock ").unwrap(), command);
+    }
+
+    #[test]
+    fn parse_rejects_unknown() {
+        assert!(ControlCommand::parse("reload").is_err());
+        assert!(ControlCommand::parse("reload   ").is_err());
+        assert!(ControlCommand::parse("refresh clock").is_err());
+        assert!(ControlCommand::parse("").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use anyhow::Context;
+
+use std::io::{Read, Write};
+
+use crate::bar::OutputFormat;
+use crate::block::Block;
+use crate::config::TomlBlock;
+use crate::protocol::Body;
+
+/// Read the output of a block's command from `input`, and write the body it
+/// would display to `out`, as an array of one body like the bar sends.
+///
+/// The block is configured with nothing but `immediate` scope, so `input` is
+/// parsed exactly as a command's output would be, with the default output
+/// format.
+///
+/// # Errors
+///
+/// - Reading `input` as UTF-8 may fail
+/// - Writing to `out` may fail
+pub fn emit<R: Read, W: Write>(mut input: R, mut out: W) -> anyhow::Result<()> {
+    let mut immediate = String::new();
+    input
+        .read_to_string(&mut immediate)
+        .context("failed to read body from standard input")?;
+
+    let local: TomlBlock = toml::from_str("").context("failed to configure block")?;
+    let body = Block::body_from_output(&immediate, &Body::new(), &local);
+
+    write!(out, "[")?;
+    OutputFormat::default().write(&mut out, &body, true)?;
+    writeln!(out, "]")?;
+    out.flush()?;
+    Ok(())
+}
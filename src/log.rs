@@ -0,0 +1,52 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+use std::io::stderr;
+
+/// Handle to the maximum level of logs, which may be changed at runtime.
+#[derive(Debug)]
+pub struct LogLevel {
+    handle: reload::Handle<LevelFilter, Registry>,
+}
+
+impl LogLevel {
+    /// Level of logs with `--terse`.
+    pub const TERSE: LevelFilter = LevelFilter::INFO;
+    /// Level of logs without `--terse`.
+    pub const VERBOSE: LevelFilter = LevelFilter::TRACE;
+
+    /// Install the global logger, initially with the maximum level `level`.
+    pub fn init(level: LevelFilter) -> Self {
+        let (filter, handle) = reload::Layer::new(level);
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(
+                fmt::layer()
+                    .with_writer(stderr)
+                    .with_timer(fmt::time::time()),
+            )
+            .init();
+        Self { handle }
+    }
+
+    /// Switch between the verbose and terse levels, returning the new level.
+    ///
+    /// # Errors
+    ///
+    /// The global logger may no longer exist.
+    pub fn toggle(&self) -> Result<LevelFilter, reload::Error> {
+        let mut new = Self::VERBOSE;
+        self.handle.modify(|level| {
+            if *level == Self::VERBOSE {
+                new = Self::TERSE;
+            }
+            *level = new;
+        })?;
+        Ok(new)
+    }
+}
@@ -1,49 +1,107 @@
 // copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
 // licensed under GPL-3.0-or-later
 
-// TODO: testing?
-
 #![forbid(unsafe_code)]
 #![warn(clippy::pedantic, clippy::cargo)]
 
 #[cfg(not(unix))]
 compile_error!("smolbar only supports Unix platforms");
 
+mod audit;
 mod bar;
+mod bench;
 mod block;
 mod blocks;
+mod click;
 mod config;
+mod control;
+mod emit;
+mod log;
+mod pidfile;
 mod protocol;
+mod watch;
 
 extern crate alloc;
 
 use anyhow::Context;
 use argh::FromArgs;
-use tokio::task;
+use serde_derive::Serialize;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::{task, time};
 use tracing::{span, Level};
 
 use core::hash::{Hash as HashTrait, Hasher};
+use core::time::Duration;
 use std::collections::hash_map::DefaultHasher;
 use std::env;
-use std::io::{self, stderr, stdout, BufWriter, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdout, BufWriter, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
 use crate::bar::Bar;
 use crate::config::Config;
+use crate::control::ControlCommand;
+use crate::log::LogLevel;
+use crate::protocol::{Body, BodyField, Header, Signal};
 
 /// smol status command for sway
-#[allow(clippy::doc_markdown)]
+#[allow(clippy::doc_markdown, clippy::struct_excessive_bools)]
 #[derive(FromArgs, Debug)]
 struct Args {
-    /// path to configuration file [default: config.toml in $XDG_CONFIG_HOME/smolbar or $HOME/.config/smolbar]
+    /// path to configuration file [default: first found of $SMOLBAR_CONFIG, config.toml in $XDG_CONFIG_HOME/smolbar or $HOME/.config/smolbar, or /etc/smolbar/config.toml]
     #[argh(option, short = 'c')]
     config: Option<PathBuf>,
 
+    /// don't canonicalize the configuration path, so symlinks are followed again on reload
+    #[argh(switch)]
+    no_canonicalize: bool,
+
+    /// write to this file or named pipe rather than standard output
+    #[argh(option, short = 'o')]
+    output: Option<PathBuf>,
+
+    /// name of the configuration profile to use [default: $SMOLBAR_PROFILE]
+    #[argh(option)]
+    profile: Option<String>,
+
+    /// send a signal (name or number) to the running smolbar, then exit
+    #[argh(option, short = 's')]
+    signal: Option<String>,
+
+    /// path to pidfile, which is only written if this or pidfile in configuration is set
+    #[argh(option)]
+    pidfile: Option<PathBuf>,
+
+    /// terminate the smolbar which claimed the pidfile, rather than refusing to start
+    #[argh(switch)]
+    replace: bool,
+
+    /// reload the configuration of the block with this name in the running smolbar, through its control socket, then exit
+    #[argh(option)]
+    reload_block: Option<String>,
+
+    /// reload the configuration whenever its file changes
+    #[argh(switch)]
+    watch: bool,
+
+    /// check that all block commands exist and are executable, then exit
+    #[argh(switch)]
+    preflight: bool,
+
+    /// write blocks as pretty JSON, for debugging
+    #[argh(switch)]
+    pretty: bool,
+
     /// decrease log verbosity
     #[argh(switch, short = 't')]
     terse: bool,
 
+    /// read a block command's output from standard input, print the body it displays, then exit
+    #[argh(switch)]
+    emit: bool,
+
     /// print license information
     #[argh(switch, short = 'l')]
     license: bool,
@@ -51,31 +109,27 @@ struct Args {
     /// print version
     #[argh(switch, short = 'V')]
     version: bool,
+
+    /// print version information as JSON
+    #[argh(switch)]
+    version_json: bool,
+
+    /// measure refresh throughput with this many synthetic blocks, then exit
+    #[argh(option, hidden_help)]
+    bench_refresh: Option<usize>,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> ExitCode {
-    fn pretty_err(err: &anyhow::Error) {
-        tracing::error!("{err}");
-        tracing::info!("because...");
-        for cause in err.chain().skip(1) {
-            tracing::info!("...{cause}");
-        }
-    }
-
     let args: Args = argh::from_env();
-    tracing_subscriber::fmt()
-        .with_writer(stderr)
-        .with_max_level(if args.terse {
-            Level::INFO
-        } else {
-            Level::TRACE
-        })
-        .with_timer(tracing_subscriber::fmt::time::time())
-        .init();
+    let log_level = LogLevel::init(if args.terse {
+        LogLevel::TERSE
+    } else {
+        LogLevel::VERBOSE
+    });
 
     #[allow(let_underscore_drop)]
-    if let Err(err) = try_main(args).await {
+    if let Err(err) = try_main(args, log_level).await {
         pretty_err(&err);
         ExitCode::FAILURE
     } else {
@@ -83,6 +137,14 @@ async fn main() -> ExitCode {
     }
 }
 
+fn pretty_err(err: &anyhow::Error) {
+    tracing::error!("{err}");
+    tracing::info!("because...");
+    for cause in err.chain().skip(1) {
+        tracing::info!("...{cause}");
+    }
+}
+
 fn print_version<W: Write>(mut out: W) -> io::Result<()> {
     writeln!(
         out,
@@ -92,17 +154,194 @@ fn print_version<W: Write>(mut out: W) -> io::Result<()> {
     )
 }
 
+/// Version information printed by `--version-json`.
+#[derive(Serialize)]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    /// Git commit hash, if `SMOLBAR_GIT_HASH` was set at build time
+    git_hash: Option<&'static str>,
+    /// Supported `swaybar-protocol(7)` version
+    protocol_version: i32,
+}
+
+fn print_version_json<W: Write>(mut out: W) -> io::Result<()> {
+    let info = VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("SMOLBAR_GIT_HASH"),
+        protocol_version: Header::DEFAULT_VERSION,
+    };
+    serde_json::to_writer(&mut out, &info)?;
+    writeln!(out)
+}
+
 fn print_license<W: Write>(mut out: W) -> io::Result<()> {
     write!(out, "{}", include_str!("../docs/COPYRIGHT"))
 }
 
-async fn try_main(args: Args) -> anyhow::Result<()> {
+/// Send `signal` to the running smolbar found through the pidfile at `path`.
+fn signal_running(signal: &str, path: &Path) -> anyhow::Result<()> {
+    /* signal numbers are resolved here rather than by kill, so that both use
+     * the same numbering */
+    let signum = match signal.parse::<i32>() {
+        Ok(signum) => signum,
+        Err(_) => signal
+            .parse::<Signal>()
+            .map_err(|()| anyhow::anyhow!(r#"unsupported signal "{signal}""#))?
+            .as_raw(),
+    };
+    let pid = pidfile::read(path).with_context(|| {
+        format!(
+            r#"failed to find running smolbar from pidfile "{}""#,
+            path.display()
+        )
+    })?;
+    pidfile::send_signal(pid, signum)
+        .with_context(|| format!("failed to send signal to smolbar (pid {pid})"))?;
+    tracing::info!(pid, signum, "sent signal");
+    Ok(())
+}
+
+/// Send `--signal` to the running smolbar, or reload its block named by
+/// `--reload-block`. It's found through the pidfile or control socket it was
+/// configured with, which are resolved the same way it did.
+fn control_running(args: Args, profile: Option<&str>) -> anyhow::Result<()> {
+    let read_config = |config| {
+        let path = get_config_path(config)?;
+        Config::read_from_path(&path, !args.no_canonicalize, profile)
+    };
+
+    if let Some(ref signal) = args.signal {
+        let path = if let Some(path) = args.pidfile {
+            path
+        } else {
+            read_config(args.config)
+                .context("failed to load config to find pidfile")?
+                .pidfile
+                .context("no pidfile to find running smolbar through (try setting `pidfile` or passing `--pidfile`)")?
+        };
+        signal_running(signal, &path)
+    } else if let Some(name) = args.reload_block {
+        let socket = read_config(args.config)
+            .context("failed to load config to find control socket")?
+            .control_socket
+            .context(
+                "no control socket to reach running smolbar through (try setting `control_socket`)",
+            )?;
+        control::send(&socket, &ControlCommand::Reload(name.clone()))?;
+        tracing::info!(name, "sent reload of block");
+        Ok(())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the first existing configuration path, from the value of
+/// `--config` and the standard locations.
+///
+/// If `--config` is searched but doesn't exist, that's an error rather than
+/// moving on to the standard locations, since it was passed explicitly.
+fn get_config_path(config: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    /* source of the path passed explicitly */
+    const EXPLICIT: &str = "`--config`";
+
+    let span = span!(Level::TRACE, "get_config_path");
+    let _enter = span.enter();
+
+    /* candidates are searched in order, and the first one which exists is
+     * used */
+    let mut candidates: Vec<(&str, PathBuf)> = Vec::with_capacity(5);
+    /* environment variables which were checked but unset, so contribute no
+     * candidate */
+    let mut unset: Vec<&str> = Vec::with_capacity(3);
+    if let Some(path) = env::var_os("SMOLBAR_CONFIG") {
+        candidates.push(("$SMOLBAR_CONFIG", PathBuf::from(path)));
+    } else {
+        unset.push("$SMOLBAR_CONFIG");
+    }
+    if let Some(path) = config {
+        candidates.push((EXPLICIT, path));
+    }
+    if let Some(xdg_config_home) = env::var_os("XDG_CONFIG_HOME") {
+        let mut path = PathBuf::from(xdg_config_home);
+        path.push("smolbar");
+        path.push("config.toml");
+        candidates.push(("$XDG_CONFIG_HOME", path));
+    } else {
+        unset.push("$XDG_CONFIG_HOME");
+    }
+    if let Some(home) = env::var_os("HOME") {
+        let mut path = PathBuf::from(home);
+        path.push(".config");
+        path.push("smolbar");
+        path.push("config.toml");
+        candidates.push(("$HOME", path));
+    } else {
+        unset.push("$HOME");
+    }
+    candidates.push(("system", PathBuf::from("/etc/smolbar/config.toml")));
+
+    for (idx, (source, path)) in candidates.iter().enumerate() {
+        if path.exists() {
+            tracing::trace!("using {source}");
+            return Ok(candidates.swap_remove(idx).1);
+        }
+        if *source == EXPLICIT {
+            return Err(anyhow::anyhow!(
+                r#"configuration file "{}" passed with {EXPLICIT} not found"#,
+                path.display()
+            ));
+        }
+        tracing::trace!(
+            path = format_args!(r#""{}""#, path.display()),
+            "{source} not found"
+        );
+    }
+
+    /* list every location tried, in order */
+    let tried =
+        |(source, path): &(&str, PathBuf)| format!(r#"tried {source} ("{}")"#, path.display());
+    let mut iter = candidates.iter().rev();
+    let mut err = anyhow::anyhow!(tried(iter.next().expect("candidates is non-empty")));
+    for candidate in iter {
+        err = err.context(tried(candidate));
+    }
+    if !unset.is_empty() {
+        err = err.context(format!("{} unset", unset.join(", ")));
+    }
+    Err(err.context("no configuration path found (try passing one with `--config`)"))
+}
+
+/// Open the file or named pipe at `path` to write the bar to.
+fn open_output(path: &Path) -> anyhow::Result<File> {
+    let is_fifo = fs::metadata(path).map_or(false, |meta| meta.file_type().is_fifo());
+    if is_fifo {
+        /* opening a fifo for writing blocks until it's opened for reading,
+         * which is fine since nothing else can happen until then */
+        tracing::info!(
+            path = format_args!(r#""{}""#, path.display()),
+            "waiting for reader of output fifo"
+        );
+        Ok(OpenOptions::new().write(true).open(path)?)
+    } else {
+        Ok(File::create(path)?)
+    }
+}
+
+async fn try_main(mut args: Args, log_level: LogLevel) -> anyhow::Result<()> {
     /* print version */
     if args.version {
         print_version(stdout())?;
         return Ok(());
     }
 
+    /* print version information as json */
+    if args.version_json {
+        print_version_json(stdout())?;
+        return Ok(());
+    }
+
     /* print license information */
     if args.license {
         let stdout = BufWriter::new(stdout());
@@ -110,36 +349,29 @@ async fn try_main(args: Args) -> anyhow::Result<()> {
         return Ok(());
     }
 
+    /* measure refresh throughput without any configuration */
+    if let Some(num_blocks) = args.bench_refresh {
+        return bench::refresh(num_blocks, stdout().lock()).await;
+    }
+
+    /* format a body without any configuration */
+    if args.emit {
+        return emit::emit(io::stdin().lock(), stdout().lock());
+    }
+
+    let profile = args.profile.take().or_else(|| {
+        env::var("SMOLBAR_PROFILE")
+            .ok()
+            .filter(|profile| !profile.is_empty())
+    });
+
+    /* control running instance, rather than starting one */
+    if args.signal.is_some() || args.reload_block.is_some() {
+        return control_running(args, profile.as_deref());
+    }
+
     /* get configuration file */
-    let path = {
-        let span = span!(Level::TRACE, "get_config_path");
-        let _enter = span.enter();
-        if let Some(path) = args.config {
-            tracing::trace!("using value of `--config`");
-            path
-        } else {
-            let config_dir = if let Some(xdg_config_home) = env::var_os("XDG_CONFIG_HOME") {
-                tracing::trace!("using $XDG_CONFIG_HOME");
-                Some(PathBuf::from(xdg_config_home))
-            } else if let Some(home) = env::var_os("HOME") {
-                tracing::trace!("using $HOME");
-                let mut dir = PathBuf::from(home);
-                dir.push(".config");
-                Some(dir)
-            } else {
-                None
-            };
-            if let Some(mut dir) = config_dir {
-                dir.push("smolbar");
-                dir.push("config.toml");
-                dir
-            } else {
-                return Err(anyhow::anyhow!(
-                    "no configuration path found (try passing one with `--config`)"
-                ));
-            }
-        }
-    };
+    let path = get_config_path(args.config)?;
 
     tracing::info!(
         path = format_args!(r#""{}""#, path.display()),
@@ -147,14 +379,75 @@ async fn try_main(args: Args) -> anyhow::Result<()> {
     );
 
     /* load configuration */
-    let config = Config::read_from_path(&path).context("failed to load config")?;
+    let config = Config::read_from_path(&path, !args.no_canonicalize, profile.as_deref())
+        .context("failed to load config")?;
 
-    /* bar runtime */
-    let mut bar = Bar::new(config);
-    bar.write_header()?;
+    /* check block commands without starting the bar */
+    if args.preflight {
+        let problems = config.check_commands();
+        if problems.is_empty() {
+            tracing::info!("all block commands are usable");
+            return Ok(());
+        }
+        for problem in &problems {
+            pretty_err(problem);
+        }
+        return Err(anyhow::anyhow!(
+            "{num} block command(s) are unusable",
+            num = problems.len()
+        ));
+    }
+
+    /* one-time setup, which blocks may depend on */
+    config
+        .run_pre_command()
+        .await
+        .context("failed to run required pre_command")?;
 
-    // start main loop
-    bar.listen().await?;
+    /* open output first, since waiting on a fifo reader may take a while */
+    let output = args
+        .output
+        .as_deref()
+        .map(|path| {
+            open_output(path)
+                .with_context(|| format!(r#"failed to open output "{}""#, path.display()))
+        })
+        .transpose()?;
+
+    /* write pidfile if configured, so that others may find this process */
+    let pidfile_path = args.pidfile.or_else(|| config.pidfile.clone());
+    if let Some(ref path) = pidfile_path {
+        pidfile::claim(path, args.replace).context("failed to claim pidfile")?;
+    }
+
+    let control_socket = config.control_socket.clone();
+
+    /* bar runtime */
+    let mut bar = if let Some(file) = output {
+        Bar::with_writer(config, file)
+    } else {
+        Bar::new(config)
+    };
+    bar.set_log_level(log_level);
+    bar.set_watch_config(args.watch);
+    bar.set_pretty(args.pretty);
+    if let Some(ref path) = control_socket {
+        control::listen(path, bar.handle()).with_context(|| {
+            format!(r#"failed to listen on control socket "{}""#, path.display())
+        })?;
+    }
+    let result = match bar.write_header() {
+        // start main loop
+        Ok(()) => bar.listen().await,
+        Err(err) => Err(err),
+    };
+    if let Some(ref path) = control_socket {
+        control::remove(path);
+    }
+    if let Some(ref path) = pidfile_path {
+        pidfile::remove(path);
+    }
+    result?;
 
     tracing::debug!("goodbye");
 
@@ -177,7 +470,32 @@ async fn await_cancellable<T>(handle: task::JoinHandle<T>) -> Option<T> {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Wait for the next delivery of the signal `kind`, received through `sig`.
+///
+/// If the stream of signals ends, the signal is registered again rather than
+/// leaving it permanently ignored.
+async fn recv_signal(sig: &mut unix::Signal, kind: SignalKind) {
+    /* delay between attempts, so a stream which keeps ending doesn't spin */
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    while sig.recv().await.is_none() {
+        tracing::warn!("signal stream ended unexpectedly, registering signal again");
+        loop {
+            time::sleep(RETRY_DELAY).await;
+            match unix::signal(kind) {
+                Ok(new) => {
+                    *sig = new;
+                    break;
+                }
+                Err(err) => {
+                    tracing::error!(err = format_args!("{err}"), "failed to register signal");
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 struct Hash(u64);
 
 impl Hash {
@@ -186,4 +504,11 @@ impl Hash {
         item.hash(&mut hasher);
         Self(hasher.finish())
     }
+
+    /// Like [`Hash::new`], but only hashing the given `fields` of `body`.
+    pub fn of_fields(body: &Body, fields: &[BodyField]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        body.hash_fields(fields, &mut hasher);
+        Self(hasher.finish())
+    }
 }
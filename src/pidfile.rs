@@ -0,0 +1,200 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use anyhow::{anyhow, Context};
+use tracing::{span, Level};
+
+use core::time::Duration;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::Instant;
+
+/// Maximum time to wait for a replaced process to terminate
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time between checks of whether a replaced process has terminated
+const REPLACE_POLL: Duration = Duration::from_millis(50);
+
+/// Write the PID of the current process to the pidfile at `path`.
+///
+/// # Errors
+///
+/// Writing to `path` may fail.
+pub fn write(path: &Path) -> anyhow::Result<()> {
+    fs::write(path, format!("{}\n", process::id())).context("failed to write pidfile")
+}
+
+/// Read the PID from the pidfile at `path`.
+///
+/// # Errors
+///
+/// - Reading from `path` may fail
+/// - `path` contents may not be a PID
+pub fn read(path: &Path) -> anyhow::Result<u32> {
+    let contents = fs::read_to_string(path).context("failed to read pidfile")?;
+    contents
+        .trim()
+        .parse()
+        .context("pidfile does not contain a valid PID")
+}
+
+/// Returns whether the process `pid` is alive.
+pub fn is_alive(pid: u32) -> bool {
+    /* signal 0 only checks that the signal could be sent */
+    send_signal(pid, 0).is_ok()
+}
+
+/// Claim the pidfile at `path` for the current process.
+///
+/// If the pidfile names another live process, this refuses to claim it,
+/// unless `replace` is true, in which case that process is asked to
+/// terminate, and waited for until it has, so that anything else it held
+/// (like its control socket) is free. A stale pidfile, whose process no
+/// longer exists, is overwritten.
+///
+/// # Errors
+///
+/// - Another process owns the pidfile, and `replace` is false
+/// - Terminating the other process may fail, or it may not terminate in time
+/// - Writing to `path` may fail
+pub fn claim(path: &Path, replace: bool) -> anyhow::Result<()> {
+    let span = span!(
+        Level::INFO,
+        "pidfile_claim",
+        path = format_args!(r#""{}""#, path.display()),
+        replace
+    );
+    let _enter = span.enter();
+
+    if path.exists() {
+        match read(path) {
+            Ok(pid) if pid != process::id() && is_alive(pid) => {
+                if !replace {
+                    return Err(anyhow!(
+                        "smolbar is already running (pid {pid}, pass `--replace` to terminate it)"
+                    ));
+                }
+                tracing::info!(pid, "terminating running smolbar");
+                send_signal(pid, libc::SIGTERM)
+                    .with_context(|| format!("failed to terminate running smolbar (pid {pid})"))?;
+                wait_for_exit(pid, REPLACE_TIMEOUT)?;
+            }
+            Ok(pid) => tracing::debug!(pid, "overwriting stale pidfile"),
+            Err(err) => tracing::debug!(err = format_args!("{err}"), "overwriting invalid pidfile"),
+        }
+    }
+
+    write(path)?;
+    tracing::trace!("wrote pidfile");
+    Ok(())
+}
+
+/// Wait until the process `pid` is no longer alive, for at most `timeout`.
+///
+/// # Errors
+///
+/// The process may still be alive after `timeout`.
+fn wait_for_exit(pid: u32, timeout: Duration) -> anyhow::Result<()> {
+    let start = Instant::now();
+    while is_alive(pid) {
+        if start.elapsed() >= timeout {
+            return Err(anyhow!(
+                "running smolbar (pid {pid}) didn't terminate within {timeout:?}"
+            ));
+        }
+        thread::sleep(REPLACE_POLL);
+    }
+    tracing::trace!(
+        pid,
+        elapsed = format_args!("{:?}", start.elapsed()),
+        "running smolbar terminated"
+    );
+    Ok(())
+}
+
+/// Remove the pidfile at `path`, if it belongs to the current process.
+pub fn remove(path: &Path) {
+    if read(path).ok() == Some(process::id()) {
+        if let Err(err) = fs::remove_file(path) {
+            tracing::warn!(err = format_args!("{err}"), "failed to remove pidfile");
+        } else {
+            tracing::trace!("removed pidfile");
+        }
+    }
+}
+
+/// Send the signal `signum` to the process `pid`.
+///
+/// Since sending a signal directly requires unsafe code, this executes
+/// `kill(1)`.
+///
+/// # Errors
+///
+/// - Executing `kill` may fail
+/// - `kill` may exit with failure, for instance if the process doesn't exist
+pub fn send_signal(pid: u32, signum: i32) -> anyhow::Result<()> {
+    kill(signum, &pid.to_string())
+}
+
+/// Send the signal `signum` to every process in the process group `pgid`,
+/// like [`send_signal`].
+///
+/// # Errors
+///
+/// - Executing `kill` may fail
+/// - `kill` may exit with failure, for instance if the group doesn't exist
+pub fn send_signal_to_group(pgid: u32, signum: i32) -> anyhow::Result<()> {
+    kill(signum, &format!("-{pgid}"))
+}
+
+fn kill(signum: i32, target: &str) -> anyhow::Result<()> {
+    let status = Command::new("kill")
+        .arg(format!("-{signum}"))
+        .arg("--")
+        .arg(target)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("failed to execute kill")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("kill exited with {status}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    /// Replacing a process waits for it to terminate before claiming its
+    /// pidfile.
+    #[test]
+    fn replace_waits_for_exit() {
+        let path = env::temp_dir().join(format!("smolbar-test-replace-{}.pid", process::id()));
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        fs::write(&path, format!("{pid}\n")).unwrap();
+        /* the child is reaped as soon as it exits, so it's no longer alive */
+        let reaper = thread::spawn(move || child.wait().unwrap());
+
+        claim(&path, true).unwrap();
+        assert!(!is_alive(pid));
+        assert_eq!(read(&path).unwrap(), process::id());
+        assert!(!reaper.join().unwrap().success());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wait_for_exit_times_out() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(wait_for_exit(child.id(), Duration::from_millis(100)).is_err());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
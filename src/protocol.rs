@@ -5,7 +5,9 @@ use cowstr::CowStr;
 use serde_derive::{Deserialize, Serialize};
 
 use core::fmt;
+use core::hash::{Hash, Hasher};
 use core::str::FromStr;
+use std::io::BufRead;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "UPPERCASE")]
@@ -45,6 +47,27 @@ impl Signal {
     }
 }
 
+impl FromStr for Signal {
+    type Err = ();
+
+    /// Parse a signal from its name, case insensitively and optionally without
+    /// the "SIG" prefix.
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        let name = if s.len() > 3 && s[..3].eq_ignore_ascii_case("sig") {
+            &s[3..]
+        } else {
+            s
+        };
+        [
+            SigAlrm, SigChld, SigCont, SigHup, SigInt, SigIo, SigPipe, SigQuit, SigStop, SigTerm,
+            SigUsr1, SigUsr2, SigWinch,
+        ]
+        .into_iter()
+        .find(|signal| signal.to_string()[3..].eq_ignore_ascii_case(name))
+        .ok_or(())
+    }
+}
+
 impl fmt::Display for Signal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -182,6 +205,10 @@ pub struct Body {
 }
 
 impl Body {
+    /// Width of each border in pixels, if the body has a border color but
+    /// doesn't set the width
+    pub const DEFAULT_BORDER_WIDTH: u32 = 1;
+
     /// Returns a new [`Body`] with all optional fields blank.
     #[must_use]
     pub const fn new() -> Self {
@@ -205,6 +232,48 @@ impl Body {
             markup: None,
         }
     }
+
+    /// Returns the name and value of the first of `color`, `background`, and
+    /// `border` which isn't a valid color (see [`is_valid_color`]), if any.
+    pub fn invalid_color(&self) -> Option<(&'static str, &str)> {
+        [
+            ("color", &self.color),
+            ("background", &self.background),
+            ("border", &self.border),
+        ]
+        .into_iter()
+        .find_map(|(field, color)| {
+            color
+                .as_deref()
+                .filter(|color| !is_valid_color(color))
+                .map(|color| (field, color))
+        })
+    }
+
+    /// Feed only the given `fields` of the body into `state`, in order.
+    pub fn hash_fields<H: Hasher>(&self, fields: &[BodyField], state: &mut H) {
+        for field in fields {
+            match field {
+                BodyField::FullText => self.full_text.hash(state),
+                BodyField::ShortText => self.short_text.hash(state),
+                BodyField::Color => self.color.hash(state),
+                BodyField::Background => self.background.hash(state),
+                BodyField::Border => self.border.hash(state),
+                BodyField::BorderTop => self.border_top.hash(state),
+                BodyField::BorderBottom => self.border_bottom.hash(state),
+                BodyField::BorderLeft => self.border_left.hash(state),
+                BodyField::BorderRight => self.border_right.hash(state),
+                BodyField::MinWidth => self.min_width.hash(state),
+                BodyField::Align => self.align.hash(state),
+                BodyField::Name => self.name.hash(state),
+                BodyField::Instance => self.instance.hash(state),
+                BodyField::Urgent => self.urgent.hash(state),
+                BodyField::Separator => self.separator.hash(state),
+                BodyField::SeparatorBlockWidth => self.separator_block_width.hash(state),
+                BodyField::Markup => self.markup.hash(state),
+            }
+        }
+    }
 }
 
 impl Default for Body {
@@ -215,10 +284,12 @@ impl Default for Body {
             color: None,
             background: None,
             border: None,
-            border_top: Some(1),
-            border_bottom: Some(1),
-            border_left: Some(1),
-            border_right: Some(1),
+            /* without a border color, no border is drawn. with one, widths
+             * default to DEFAULT_BORDER_WIDTH when the config is loaded. */
+            border_top: None,
+            border_bottom: None,
+            border_left: None,
+            border_right: None,
             min_width: None,
             align: Some(Align::Left),
             name: None,
@@ -231,6 +302,53 @@ impl Default for Body {
     }
 }
 
+/// Name of a field of [`Body`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyField {
+    FullText,
+    ShortText,
+    Color,
+    Background,
+    Border,
+    BorderTop,
+    BorderBottom,
+    BorderLeft,
+    BorderRight,
+    MinWidth,
+    Align,
+    Name,
+    Instance,
+    Urgent,
+    Separator,
+    SeparatorBlockWidth,
+    Markup,
+}
+
+/// Returns whether `color` is in #RRGGBBAA or #RRGGBB notation, as required
+/// by `swaybar-protocol(7)`.
+pub fn is_valid_color(color: &str) -> bool {
+    color.strip_prefix('#').map_or(false, |hex| {
+        matches!(hex.len(), 6 | 8) && hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+    })
+}
+
+/// Returns `text` escaped for use in [Pango markup](Markup::Pango).
+pub fn escape_markup(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\'' => escaped.push_str("&apos;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// [Body alignment](Body::align), as defined in `swaybar-protocol(7)`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -286,8 +404,7 @@ impl FromStr for Markup {
 }
 
 /// Click event, as defined in `swaybar-protocol(7)`.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct ClickEvent {
     /// "The name of the block, if set"
     pub name: Option<CowStr>,
@@ -311,3 +428,186 @@ pub struct ClickEvent {
     /// "The height of the block in pixels"
     pub height: u32,
 }
+
+/* written by hand so that `name` and `instance` are shown as plain strings,
+ * since the debug representation of `CowStr` is verbose */
+impl fmt::Debug for ClickEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClickEvent")
+            .field("name", &self.name.as_deref())
+            .field("instance", &self.instance.as_deref())
+            .field("x", &self.x)
+            .field("y", &self.y)
+            .field("button", &self.button)
+            .field("event", &self.event)
+            .field("relative_x", &self.relative_x)
+            .field("relative_y", &self.relative_y)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+/// Reader of [`ClickEvent`]s from the stream a bar writes to a status
+/// command's standard input, if click events are enabled.
+///
+/// The stream is an infinite JSON array, whose elements are written as the
+/// clicks happen: it starts with `[`, and every event after the first is
+/// preceded by `,`, with any whitespace in between.
+#[derive(Debug)]
+pub struct ClickEventReader<R> {
+    reader: R,
+    /// Whether an error was encountered from which the stream can't be
+    /// resynchronized
+    failed: bool,
+}
+
+impl<R: BufRead> ClickEventReader<R> {
+    pub const fn new(reader: R) -> Self {
+        Self {
+            reader,
+            failed: false,
+        }
+    }
+
+    /// Skip whitespace, the opening `[`, and any `,` up to the next event.
+    /// Returns whether there is one, rather than the end of the stream.
+    fn skip_separators(&mut self) -> std::io::Result<bool> {
+        loop {
+            let Some(&byte) = self.reader.fill_buf()?.first() else {
+                return Ok(false);
+            };
+            match byte {
+                b' ' | b'\t' | b'\n' | b'\r' | b'[' | b',' => self.reader.consume(1),
+                /* the array is never closed in practice, but if it is, there
+                 * are no more events */
+                b']' => return Ok(false),
+                _ => return Ok(true),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ClickEventReader<R> {
+    /// An event, or an error reading or parsing it. After an event which
+    /// isn't a valid [`ClickEvent`], reading continues with the next one.
+    /// After malformed JSON, the rest of its line is skipped, since bars
+    /// write each event on its own line. After any other error, no more
+    /// events are read.
+    type Item = serde_json::Result<ClickEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.skip_separators() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(serde_json::Error::io(err)));
+            }
+        }
+
+        /* the event is parsed as any value first, so that the stream stays
+         * in sync even if it's not a valid event */
+        let mut values = serde_json::Deserializer::from_reader(&mut self.reader)
+            .into_iter::<serde_json::Value>();
+        let value = match values.next()? {
+            Ok(value) => value,
+            Err(err) if err.is_syntax() => {
+                if let Err(err) = self.reader.read_until(b'\n', &mut Vec::new()) {
+                    self.failed = true;
+                    return Some(Err(serde_json::Error::io(err)));
+                }
+                return Some(Err(err));
+            }
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        };
+        Some(serde_json::from_value(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a click event on the block named `name`, as a bar writes it.
+    fn event(name: &str) -> String {
+        format!(
+            r#"{{"name":"{name}","x":1,"y":2,"button":1,"event":272,"relative_x":3,"relative_y":4,"width":5,"height":6}}"#
+        )
+    }
+
+    /// Returns the names of the blocks clicked in `input`, or `None` for each
+    /// error.
+    fn read(input: &str) -> Vec<Option<String>> {
+        ClickEventReader::new(input.as_bytes())
+            .map(|event| event.ok().map(|event| event.name.unwrap().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_events_as_written_by_swaybar() {
+        let input = format!("[\n{}\n,{}\n,{}\n", event("a"), event("b"), event("c"));
+        assert_eq!(
+            read(&input),
+            [Some("a".into()), Some("b".into()), Some("c".into())]
+        );
+    }
+
+    #[test]
+    fn reads_all_fields() {
+        let event = ClickEventReader::new(format!("[{}", event("a")).as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.instance, None);
+        assert_eq!((event.x, event.y), (1, 2));
+        assert_eq!((event.button, event.event), (1, 272));
+        assert_eq!((event.relative_x, event.relative_y), (3, 4));
+        assert_eq!((event.width, event.height), (5, 6));
+    }
+
+    #[test]
+    fn skips_whitespace() {
+        let input = format!(" \t[ \r\n\n{} \n\t, \r\n {}", event("a"), event("b"));
+        assert_eq!(read(&input), [Some("a".into()), Some("b".into())]);
+    }
+
+    #[test]
+    fn nothing_before_first_event() {
+        assert!(read("").is_empty());
+        assert!(read("[\n").is_empty());
+        assert!(read("[]").is_empty());
+    }
+
+    #[test]
+    fn eof_mid_event_ends_stream() {
+        let input = format!("[\n{}\n,{{\"name\":\"b\",\"x\":", event("a"));
+        assert_eq!(read(&input), [Some("a".into()), None]);
+    }
+
+    #[test]
+    fn malformed_event_is_skipped() {
+        let input = format!("[\n{{\"name\":]\n,{}\n", event("a"));
+        assert_eq!(read(&input), [None, Some("a".into())]);
+    }
+
+    #[test]
+    fn invalid_event_is_skipped() {
+        let input = format!("[\n{{\"name\":\"a\"}}\n,{}\n", event("b"));
+        assert_eq!(read(&input), [None, Some("b".into())]);
+    }
+
+    /// Bars may send fields which aren't in `swaybar-protocol(7)`, like
+    /// i3bar's `modifiers`.
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let event = event("a").replacen('{', r#"{"modifiers":["Shift"],"#, 1);
+        assert_eq!(read(&format!("[\n{event}\n")), [Some("a".into())]);
+    }
+}
@@ -0,0 +1,181 @@
+// copyright (C) 2022-2023 Ula Shipman <ula.hello@mailbox.org>
+// licensed under GPL-3.0-or-later
+
+use tokio::time;
+
+use core::time::Duration;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What identifies the contents of a file: its device, inode, modification
+/// time, and length.
+type Stamp = (u64, u64, Option<SystemTime>, u64);
+
+/// Watcher of a file, which is polled for changes rather than relying on
+/// notifications from the operating system.
+///
+/// Polling needs no dependency or platform-specific API, like inotify, and
+/// follows the path rather than the file, so a file replaced by renaming
+/// another over it is still watched without also watching its directory.
+/// Checking a few files with `stat` twice a second is cheap, and the delay
+/// it adds is hardly noticeable while editing.
+#[derive(Debug)]
+pub struct FileWatcher {
+    path: PathBuf,
+    /// Stamp of the file when it was last checked, or `None` if it couldn't
+    /// be read
+    seen: Option<Stamp>,
+    interval: time::Interval,
+}
+
+impl FileWatcher {
+    /// How often the file is checked for changes
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new(path: PathBuf) -> Self {
+        let seen = stamp(&path);
+        let mut interval = time::interval(Self::INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        Self {
+            path,
+            seen,
+            interval,
+        }
+    }
+
+    /// Returns whether the file existed when it was last checked.
+    pub const fn exists(&self) -> bool {
+        self.seen.is_some()
+    }
+
+    /// Wait until the file changes, and then stops changing for one check.
+    /// Editors may save a file in several steps, like writing a new file and
+    /// renaming it over the old one, which this treats as one change. The
+    /// file being removed isn't a change until it's back.
+    pub async fn changed(&mut self) {
+        let mut changed = false;
+        loop {
+            self.interval.tick().await;
+            let stamp = stamp(&self.path);
+            if stamp != self.seen {
+                self.seen = stamp;
+                changed = true;
+            } else if changed && stamp.is_some() {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns what identifies the current contents of the file at `path`, or
+/// `None` if it can't be read. If the file is replaced, rather than modified
+/// in place, this changes too.
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((
+        metadata.dev(),
+        metadata.ino(),
+        metadata.modified().ok(),
+        metadata.len(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::{env, process};
+
+    /// Longer than it takes to notice a change which settled
+    const SETTLED: Duration = Duration::from_secs(2);
+
+    /// Returns the path of a new file named `name`, in a directory removed
+    /// by the caller.
+    fn file(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("smolbar-test-watch-{name}-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, "a").unwrap();
+        path
+    }
+
+    fn append(path: &Path) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"a").unwrap();
+    }
+
+    async fn changes_within(watcher: &mut FileWatcher, timeout: Duration) -> bool {
+        time::timeout(timeout, watcher.changed()).await.is_ok()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unchanged_file() {
+        let path = file("unchanged");
+        let mut watcher = FileWatcher::new(path.clone());
+        assert!(watcher.exists());
+        assert!(!changes_within(&mut watcher, SETTLED).await);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn modified_file() {
+        let path = file("modified");
+        let mut watcher = FileWatcher::new(path.clone());
+        append(&path);
+        assert!(changes_within(&mut watcher, SETTLED).await);
+        assert!(!changes_within(&mut watcher, SETTLED).await);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// A file which keeps changing isn't a change until it stops.
+    #[tokio::test(start_paused = true)]
+    async fn debounced_until_file_stops_changing() {
+        let path = file("debounced");
+        let mut watcher = FileWatcher::new(path.clone());
+        let changed = async {
+            watcher.changed().await;
+            time::Instant::now()
+        };
+        let writing = async {
+            for _ in 0..8 {
+                append(&path);
+                time::sleep(FileWatcher::INTERVAL / 2).await;
+            }
+            time::Instant::now()
+        };
+        let (changed_at, stopped_at) = tokio::join!(changed, writing);
+        assert!(changed_at >= stopped_at, "{changed_at:?} < {stopped_at:?}");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Replacing the file by renaming another over it is a change, even if
+    /// the new file is the same length.
+    #[tokio::test(start_paused = true)]
+    async fn replaced_by_rename() {
+        let path = file("renamed");
+        let mut watcher = FileWatcher::new(path.clone());
+        let new = path.with_extension("new");
+        fs::write(&new, "b").unwrap();
+        fs::rename(&new, &path).unwrap();
+        assert!(changes_within(&mut watcher, SETTLED).await);
+        assert!(watcher.exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// A removed file isn't a change until it's back.
+    #[tokio::test(start_paused = true)]
+    async fn removed_until_back() {
+        let path = file("removed");
+        let mut watcher = FileWatcher::new(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert!(!changes_within(&mut watcher, SETTLED).await);
+        assert!(!watcher.exists());
+        fs::write(&path, "b").unwrap();
+        assert!(changes_within(&mut watcher, SETTLED).await);
+        assert!(watcher.exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}